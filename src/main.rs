@@ -1,27 +1,62 @@
 #![allow(unused_variables, unused_imports, unused_assignments)]
-use std::{path::PathBuf, str::FromStr, io::{Write, Read}, error::Error, fs::File, f32::consts::E};
+use std::{path::PathBuf, str::FromStr, io::{Write, Read}, error::Error, fs::File, f32::consts::E, collections::BTreeMap};
 
 use async_hwi::{ledger::{self, HidApi, Ledger, TransportHID}, HWI};
-use bitcoin::{bip32::DerivationPath, psbt::Psbt};
+use bitcoin::{absolute::LockTime, bip32::DerivationPath, psbt::Psbt, Address, Network, OutPoint, Sequence, Txid};
 use clap::{Parser, Subcommand, Args};
-use coinline::{server::server::{get_balance, get_fresh, get_tx_history, UserTransaction, get_all_fee_estimates, get_all_utxo}, wallet::actions::{is_valid_fp, is_valid_xpub, compute_address, make_and_download_transaction, make_and_send_to_ledger, print_psbt, extract_broadcast}, system::system::{import_coldcard_from_json, import_keystone_from_txt}};
+use coinline::{server::{server::{get_balance, get_fresh, get_tx_history, UserTransaction, get_all_fee_estimates, get_fee_estimate, get_all_utxo, default_server, sync}, cache::WalletCache, source::{connect, ChainSource, ServerSettings}, pool::ServerPool, tls::certificate_fingerprint}, wallet::{actions::{SelectionUTXO, is_valid_fp, is_valid_xpub, compute_address, make_and_download_transaction, make_and_send_to_ledger, print_psbt, extract_broadcast, account_path, taproot_account_path, single_sig_descriptor, taproot_descriptor, WalletDescriptor, multisig_descriptor, multisig_account_path, combine_psbts, print_signatures, is_fully_signed, download_psbt, Recipient, CoinControl, TxOptions, make_bump, make_cancel, make_consolidation, make_cpfp}, selection::ALGORITHMS, uri::{is_payment_uri, parse_payment_uri, payment_uri, PaymentRequest}}, system::system::{import_coldcard_from_json, import_keystone_from_txt, import_recipients}};
 use colored::Colorize;
 use qrcode::QrCode;
 use qrcode::render::unicode;
-use serde::{Serialize, Deserialize, __private::de};
+use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct WalletConfig {
-    gap: u8,
     client: String,
+    /// Servers of the same backend tried in order when the client cannot be reached.
+    fallbacks: Vec<String>,
+    backend: String,
+    /// Bitcoin Core RPC credentials: user:password or the path to the cookie file.
+    auth: String,
+    /// A SOCKS5 proxy, such as Tor, that server connections are routed through. Empty connects directly.
+    proxy: String,
+    network: Network,
+    current: String,
+    // tables are written after every plain value
+    /// Certificate fingerprints of Electrum servers trusted on first use, by URL.
+    certificates: BTreeMap<String, String>,
+    wallets: BTreeMap<String, WalletEntry>,
+    // configurations saved before named wallets hold a single wallet, which is migrated on load
+    #[serde(skip_serializing)]
+    gap: u8,
+    #[serde(skip_serializing)]
+    descriptor: String,
+    #[serde(skip_serializing)]
+    hmac: [u8; 32],
+    #[serde(skip_serializing)]
     fp: String,
+    #[serde(skip_serializing)]
     xpub: String,
-    hmac: [u8; 32],
 }
 
 impl ::std::default::Default for WalletConfig {
-    fn default() -> Self { Self { gap: 20, client: "ssl://electrum.blockstream.info:50002".into(), fp: "".into(), xpub: "".into(), hmac: [0; 32] } }
+    fn default() -> Self { Self { client: default_server(Network::Bitcoin), fallbacks: Vec::new(), backend: "electrum".into(), auth: "".into(), proxy: "".into(), certificates: BTreeMap::new(), network: Network::Bitcoin, current: "".into(), wallets: BTreeMap::new(), gap: 20, descriptor: "".into(), hmac: [0; 32], fp: "".into(), xpub: "".into() } }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WalletEntry {
+    descriptor: String,
+    device: String,
+    gap: u8,
+    hmac: [u8; 32],
+    /// Coins that coin selection never spends.
+    frozen: Vec<OutPoint>,
+}
+
+impl ::std::default::Default for WalletEntry {
+    fn default() -> Self { Self { descriptor: "".into(), device: "".into(), gap: 20, hmac: [0; 32], frozen: Vec::new() } }
 }
 
 #[derive(Parser)]
@@ -30,14 +65,25 @@ impl ::std::default::Default for WalletConfig {
 pub struct CoinlineArgs {
     #[command(subcommand)]
     command: Commands,
+    /// The name of the wallet to use instead of the one selected with the 'use' command.
+    #[arg(long, global = true)]
+    wallet: Option<String>,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Configures your wallet with Coinline using the Master Fingerprint and Native SegWit Extended Public Key.
+    /// Configures your wallet with Coinline using the Master Fingerprint and Native SegWit, or Taproot, Extended Public Key.
     Set(Set),
+    /// Configures your wallet with Coinline using an output descriptor, e.g. wpkh([fingerprint/84h/0h/0h]xpub/<0;1>/*).
+    SetDescriptor(SetDescriptor),
+    /// Configures an m-of-n Native Segwit multisig wallet from the fingerprint and extended public key of every cosigner.
+    SetMultisig(SetMultisig),
+    /// Registers the configured wallet with a connected Ledger so it can sign. Required for multisig wallets that include a Ledger.
+    RegisterLedger,
+    /// Combines PSBTs signed by different cosigners into one, saved in your downloads folder.
+    Combine(Combine),
     /// Sets the current device to Ledger, if plugged in.
-    SetLedger,
+    SetLedger(SetLedger),
     /// Sets the current using a configuration file from your Keystone or Coldcard.
     SetFile(SetFile),
     /// Returns your configuration file.
@@ -45,33 +91,91 @@ pub enum Commands {
     /// Gets the balance for the configured wallet. Balance is returned in Satoshis.
     Balance(Balance),
     /// Gets the next unused address for the wallet with the specified name.
-    Receive,
-    /// Gets the history of the transactions on this wallet. The history is reconstructed with no local cache, so this may take a while.
+    Receive(Receive),
+    /// Gets the history of the transactions on this wallet. Transactions are kept in a local cache, so only new activity is downloaded.
     History(History),
+    /// Discards the local cache for the wallet and rebuilds it from the server.
+    Rescan,
     /// Prepares a transaction to send.
     Send(Send),
-    /// Queries the Electrum server to get fee estimates for transactions settling between 1-25 blocks.
+    /// Replaces an unconfirmed transaction you sent with one paying a higher fee, so it confirms sooner.
+    Bump(Bump),
+    /// Cancels an unconfirmed transaction you sent by replacing it with one returning the coins to your wallet at a higher fee.
+    Cancel(Bump),
+    /// Merges small coins into one at a low fee rate, so they cost less to spend when fees are higher.
+    Consolidate(Consolidate),
+    /// Speeds up an unconfirmed payment you received by spending it with a higher fee, so both confirm together.
+    Cpfp(Bump),
+    /// Queries the server to get fee estimates for transactions settling between 1-25 blocks.
     Fees,
-    /// Broadcast a signed transaction to the network via Electrum.
+    /// Broadcast a signed transaction to the network via the configured server.
     Broadcast(Broadcast),
     /// Tests if there is a Ledger hardware wallet connected.
     Ledger,
+    /// Lists the unspent outputs of the wallet with their value, address, derivation path and confirmations.
+    Utxos,
     /// Finds UTXOs lower than the amount provided. Accepts arguments between 500 and 10000 Satoshis.
     Dust(Dust),
     /// Return the first 10 receiving and change addresses from your device 
     Addresses,
-    /// Sets the prefered Electrum client using a URL. Expects the URL to be [tcp/ssl]://[server_name]:[port]. Default is ssl://electrum.blockstream.info:50002.
+    /// Sets the prefered Electrum, Esplora or Bitcoin Core server using a URL. Electrum URLs are [tcp/ssl]://[server_name]:[port]. Default is ssl://electrum.blockstream.info:50002.
     Client(Client),
+    /// Routes every server connection through a SOCKS5 proxy, such as Tor at 127.0.0.1:9050. Required for .onion servers. Use 'none' to connect directly.
+    Proxy(Proxy),
     /// Sets the prefered gap in no-actvity addresses until the program quits. Valid gaps are between [1, 50].
     Gap(Gap),
+    /// Sets the network the wallets operate on: bitcoin, testnet, signet or regtest. Wallets for a different network cannot be used until you switch back.
+    Network(NetworkArg),
+    /// Lists the configured wallets.
+    Wallets,
+    /// Selects the wallet every command uses by default.
+    Use(Use),
+    /// Marks coins so they are never chosen by coin selection, for example dust sent to track you. They can still be spent with 'send --include'.
+    Freeze(Outpoints),
+    /// Lets coin selection spend coins that were frozen.
+    Unfreeze(Outpoints),
+    /// Lists the frozen coins of the wallet.
+    Frozen,
 }
 
 #[derive(Debug, Args)]
 pub struct Set {
     /// The master fingerprint of your wallet. Found at path m/. More information on setting a wallet, visit https://coinline.io
     fingerprint: String,
-    /// The Native Segwit extended public key of your wallet. Found at path m/84h/0h/0h/
+    /// The Native Segwit extended public key of your wallet. Found at path m/84h/0h/0h/ on mainnet and m/84h/1h/0h/ on the test networks.
     xpub: String,
+    /// Use a Taproot account instead. The extended public key is then found at path m/86h/0h/0h/ on mainnet and m/86h/1h/0h/ on the test networks.
+    #[arg(long)]
+    taproot: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct SetLedger {
+    /// Use the Taproot account at m/86h/0h/0h instead of Native Segwit.
+    #[arg(long)]
+    taproot: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct SetDescriptor {
+    /// The output descriptor of your wallet, with a <0;1> receive and change path ending in a wildcard.
+    descriptor: String,
+}
+
+#[derive(Debug, Args)]
+pub struct SetMultisig {
+    /// The number of signatures required to spend.
+    threshold: usize,
+    /// Each cosigner as [fingerprint]:[xpub] at path m/48h/0h/0h/2h, a key with its origin like [fingerprint/48h/0h/0h/2h]xpub, or 'ledger' to read the connected Ledger.
+    #[arg(required = true)]
+    cosigners: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct Combine {
+    /// The paths to the PSBTs signed by each cosigner.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
 }
 
 #[derive(Debug, Args)]
@@ -94,8 +198,26 @@ pub struct Fee {
 
 #[derive(Debug, Args)]
 pub struct Client {
-    /// The preferred Electru, client in the form: [tcp/ssl]://[server_name]:[port]
+    /// The preferred server. Electrum servers take the form [tcp/ssl]://[server_name]:[port], Esplora servers are the URL of the API, e.g. https://mempool.space/api, and Bitcoin Core is the RPC URL, e.g. http://127.0.0.1:8332
     client: String,
+    /// The kind of server: electrum, esplora or bitcoind.
+    #[arg(long, default_value = "electrum")]
+    backend: String,
+    /// Bitcoin Core RPC credentials, either user:password or the path to the node's .cookie file.
+    #[arg(long)]
+    auth: Option<String>,
+    /// A server of the same backend to use when the others cannot be reached. May be given more than once, and is tried in order.
+    #[arg(long = "fallback")]
+    fallbacks: Vec<String>,
+    /// Trust the certificate each ssl:// Electrum server presents now, for self-signed servers, and refuse any other certificate later. Run again to trust a changed certificate.
+    #[arg(long)]
+    trust: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct Proxy {
+    /// The proxy address in the form [host]:[port], optionally starting with socks5://, or none.
+    proxy: String,
 }
 
 #[derive(Debug, Args)]
@@ -112,6 +234,62 @@ pub struct Gap {
     gap: u8,
 }
 
+#[derive(Debug, Args)]
+pub struct Receive {
+    /// The amount to request, in Satoshis, added to the payment URI in the QR code.
+    #[arg(long)]
+    amount: Option<u64>,
+    /// A label for the payment, such as your name, added to the payment URI.
+    #[arg(long)]
+    label: Option<String>,
+    /// A message describing the payment, added to the payment URI.
+    #[arg(long)]
+    message: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct Bump {
+    /// How the new transaction is signed, "file" or "ledger", as with 'send'.
+    signer: String,
+    /// The unconfirmed transaction to speed up.
+    txid: Txid,
+    /// How many estimated blocks in the future for the transaction to be confirmed.
+    blocks: u8,
+}
+
+#[derive(Debug, Args)]
+pub struct Consolidate {
+    /// How the transaction is signed, "file" or "ledger", as with 'send'.
+    signer: String,
+    /// The fee rate to pay, in sat/vB. Consolidating saves the most when this is low.
+    fee_rate: f64,
+    /// Only merge coins worth less than this many Satoshis. Every coin is merged without it.
+    #[arg(long)]
+    below: Option<u64>,
+    /// The fee rate, in sat/vB, expected when the coins would otherwise be spent, used to report the savings. Defaults to the current estimate for 6 blocks.
+    #[arg(long)]
+    future: Option<f64>,
+}
+
+#[derive(Debug, Args)]
+pub struct Outpoints {
+    /// The coins, each given as [txid]:[vout].
+    #[arg(required = true)]
+    outpoints: Vec<OutPoint>,
+}
+
+#[derive(Debug, Args)]
+pub struct Use {
+    /// The name of a configured wallet.
+    name: String,
+}
+
+#[derive(Debug, Args)]
+pub struct NetworkArg {
+    /// The network to use, one of [bitcoin, testnet, signet, regtest].
+    network: Network,
+}
+
 #[derive(Debug, Args)]
 pub struct Balance {
     /// The amount of addresses with empty UTXO balances until the program quits looking for new UTXOs.
@@ -128,15 +306,150 @@ pub struct Broadcast {
 pub struct Send {
     /// How your transaction signing will occur. For an airgapped work-flow, save the PSBT by passing "file." For Ledger, pass "ledger".
     signer: String,
-    /// The address you are sending to.
+    /// The address you are sending to, or a bitcoin: payment URI.
     receiving: String,
-    /// How much you are sending to the receive address, in Satoshis.
-    value: u64,
+    /// How much you are sending to the receive address, in Satoshis. Pass "max" to send the entire balance, less the fee, without change. May be left out when the payment URI has an amount, followed directly by the blocks.
+    value: SendValue,
     /// How many estimated blocks in the future for this transaction to be confirmed.
-    blocks: u8,
-    /// How to select the UTXOs to fund the transcation, default is largest first: options are [smallest, largest]. More information on coin selection at https://coinline.io
+    blocks: Option<String>,
+    /// How to select the UTXOs to fund the transcation, default is largest first: options are [smallest, largest, bnb, random, waste]. "bnb" searches for a transaction without change, "random" draws coins at random, and "waste" picks whichever selection wastes the least in fees given current and long-term fee rates. More information on coin selection at https://coinline.io
     algorithm: Option<String>,
+    /// Another payment in the same transaction, as [address]:[amount]. May be given more than once.
+    #[arg(long = "to")]
+    to: Vec<String>,
+    /// A file of further payments, either CSV lines of address,amount or a JSON list of {"address", "amount"} objects.
+    #[arg(long)]
+    batch: Option<PathBuf>,
+    /// Spend this coin, given as [txid]:[vout]. May be given more than once. When used, only these coins are spent, and all of them are.
+    #[arg(long)]
+    include: Vec<OutPoint>,
+    /// Never spend this coin, given as [txid]:[vout]. May be given more than once.
+    #[arg(long)]
+    exclude: Vec<OutPoint>,
+    /// The block height, or Unix time from 500000000, before which the transaction cannot be mined. Defaults to the current height, which discourages fee sniping.
+    #[arg(long)]
+    locktime: Option<u32>,
+    /// How many blocks each spent coin must have been confirmed for before the transaction can be mined.
+    #[arg(long)]
+    sequence: Option<u16>,
+}
+
+/// How much a send pays its first recipient.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendValue {
+    /// The whole balance, less the fee.
+    Max,
+    Sats(u64),
+}
+
+impl FromStr for SendValue {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "max" => Ok(SendValue::Max),
+            sats => sats.parse::<u64>().map(SendValue::Sats).map_err(|_| "the value must be a number of Satoshis or \"max\"".to_string()),
+        }
+    }
+}
+
+/// Reads the arguments of a send after the recipient: [value] [blocks] [algorithm]. When the payment URI has an
+/// amount the value may be left out, and the arguments are read as [blocks] [algorithm], with no value.
+fn send_positionals(uri_amount: Option<u64>, value: SendValue, blocks: Option<&str>, algorithm: Option<&str>) -> Result<(Option<SendValue>, u8, Option<String>), String> {
+    let invalid_blocks = |blocks: &str| format!("The blocks must be a number up to 255, not {blocks}");
+    // an algorithm name where the blocks would be, or nothing at all, means the value was left out
+    let shifted = uri_amount.is_some() && algorithm.is_none() && blocks.is_none_or(|blocks| blocks.parse::<u8>().is_err());
+    if shifted {
+        let SendValue::Sats(target) = value else { return Err(invalid_blocks("max")) };
+        let target = u8::try_from(target).map_err(|_| invalid_blocks(&target.to_string()))?;
+        return Ok((None, target, blocks.map(String::from)));
+    }
+    let blocks = blocks.ok_or("Please give the number of blocks the transaction should confirm in")?;
+    let blocks = blocks.parse::<u8>().map_err(|_| invalid_blocks(blocks))?;
+    Ok((Some(value), blocks, algorithm.map(String::from)))
+}
+
+fn configured_wallet(cfg: &WalletConfig, name: &str) -> Option<(WalletEntry, WalletDescriptor)> {
+    let Some(entry) = cfg.wallets.get(name) else {
+        if cfg.wallets.is_empty() {
+            println!("\nPlease configure a wallet by using the 'set', 'set-descriptor', 'set-multisig', 'set-file' or 'set-ledger' command.");
+        } else {
+            println!("\nThere is no wallet named {}. Use the 'wallets' command to list them.", name.bright_yellow());
+        }
+        return None;
+    };
+    match WalletDescriptor::new(&entry.descriptor, cfg.network) {
+        Ok(wallet) => Some((entry.clone(), wallet)),
+        Err(e) => {
+            let warn = format!("The descriptor for {name} could not be used: {e}").bright_yellow();
+            println!("\n{warn}\n");
+            None
+        }
+    }
+}
+
+fn save_descriptor(mut cfg: WalletConfig, name: &str, device: &str, descriptor: String, hmac: [u8; 32]) {
+    let wallet = match WalletDescriptor::new(&descriptor, cfg.network) {
+        Ok(wallet) => wallet,
+        Err(e) => {
+            let warn = format!("The descriptor could not be saved: {e}").bright_yellow();
+            println!("\n{warn}\n");
+            return;
+        }
+    };
+    let address = compute_address(&wallet, true, 0).expect("Could not get address.");
+    let confirmation = address.to_string().bright_green();
+    println!("Please confirm that this is your first address [receiving]: {confirmation}\n");
+    let previous = cfg.wallets.remove(name).unwrap_or_default();
+    cfg.wallets.insert(name.into(), WalletEntry { descriptor, device: device.into(), gap: previous.gap, hmac, frozen: previous.frozen });
+    if cfg.current.is_empty() {
+        cfg.current = name.into();
+    }
+    confy::store("coinline", None, cfg).expect("save configuration error");
+    let confirm = format!("Your wallet was saved as {name}").bright_green();
+    println!("{confirm}\n");
+}
+
+fn server_settings(cfg: &WalletConfig) -> ServerSettings {
+    ServerSettings { backend: cfg.backend.clone(), auth: cfg.auth.clone(), proxy: cfg.proxy.clone(), certificates: cfg.certificates.clone() }
+}
+
+fn chain_source(cfg: &WalletConfig) -> Box<dyn ChainSource> {
+    let servers = [vec![cfg.client.clone()], cfg.fallbacks.clone()].concat();
+    let source = ServerPool::connect(servers, server_settings(cfg)).expect("Could not connect to the server");
+    let msg = format!("\nConnected to an {} server\n", cfg.backend).bright_green();
+    eprintln!("{msg}");
+    Box::new(source)
+}
+
+/// Loads the wallet's cache and brings it up to date with the server.
+fn synced_cache(cfg: &WalletConfig, source: &dyn ChainSource, name: &str, wallet: &WalletDescriptor, gap: u8) -> WalletCache {
+    let mut cache = WalletCache::load(name, wallet);
+    sync(wallet, gap, source, &mut cache).expect("Global Error Syncing The Wallet");
+    cache.save(name, cfg.network).expect("Could not save the wallet cache");
+    cache
+}
 
+/// The coins a send may spend. Frozen coins are left out unless the user picked the coins to spend.
+fn send_coin_control(include: &[OutPoint], exclude: &[OutPoint], frozen: &[OutPoint]) -> CoinControl {
+    let mut coin_control = CoinControl { include: include.to_vec(), exclude: exclude.to_vec() };
+    // coins the user picks are spent even when frozen
+    if include.is_empty() {
+        coin_control.exclude.extend(frozen.iter().copied());
+    }
+    coin_control
+}
+
+/// The coins worth less than the threshold that are not frozen yet.
+fn find_dust<'a>(utxos: &'a [SelectionUTXO], threshold: u64, frozen: &[OutPoint]) -> Vec<&'a SelectionUTXO> {
+    utxos.iter().filter(|utxo| utxo.value < threshold && !frozen.contains(&utxo.outpoint())).collect()
+}
+
+fn read_psbt(path: &PathBuf) -> Psbt {
+    let mut file = File::open(path).expect("Could not find that PSBT");
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).expect("Error reading that PSBT");
+    Psbt::deserialize(&buffer).expect("Error deserializing that PSBT")
 }
 
 fn get_user_approval() -> Result<bool, Box<dyn Error>> {
@@ -156,12 +469,71 @@ fn get_user_approval() -> Result<bool, Box<dyn Error>> {
     }
 }
 
-fn print_balance(bal: i64) -> () {
+fn connected_ledger() -> Option<Ledger<TransportHID>> {
+    let api = HidApi::new().ok()?;
+    let detected = Ledger::<TransportHID>::enumerate(&api).next()?;
+    Ledger::<TransportHID>::connect(&api, detected).ok()
+}
+
+/// Signs the PSBT with the Ledger. A fully signed transaction is shown for approval before it is broadcast,
+/// otherwise it is saved for the other cosigners.
+async fn ledger_sign_and_broadcast(device: Ledger<TransportHID>, mut psbt: Psbt, entry: &WalletEntry, wallet: &WalletDescriptor, network: Network, source: &dyn ChainSource) {
+    let pol = wallet.ledger_policy();
+    if entry.hmac.eq(&[0; 32]) {
+        println!("HMAC retrieval error");
+        return;
+    }
+    let ok = "OK".bright_green();
+    println!("\nPlease check your Ledger");
+    println!("If you do not use Ledger Live, you make get an unverified inputs message. This is {ok}\n");
+    let hmac = Some(entry.hmac);
+    let res = device.with_wallet("Coinline", &pol, hmac).unwrap().sign_tx(&mut psbt).await;
+    match res {
+        Ok(res) => { println!("\nYour transaction has been signed by your Ledger\n") },
+        Err(_) => {
+            println!("\nYour transaction was not signed by your device. Exiting...\n");
+            return;
+        }
+    }
+    if !is_fully_signed(&psbt) {
+        print_signatures(&psbt, wallet);
+        download_psbt(&psbt, "partially_signed.psbt").expect("Could not save PSBT");
+        println!("Have your other cosigners sign this PSBT, then use the 'combine' and 'broadcast' commands.\n");
+        return;
+    }
+    print_psbt(psbt.clone(), wallet, network).expect("Could not print PSBT");
+    if let Ok(approved) = get_user_approval() {
+        if approved {
+            extract_broadcast(psbt, source, network).expect("Finalization error");
+        } else {
+            let deny = "Broadcast not approved. Exiting...".bright_yellow();
+            println!("\n{deny}\n");
+        }
+    }
+}
+
+/// Hands a PSBT to the signer: saved to a file for an airgapped device, or signed and broadcast with a Ledger.
+async fn sign_psbt(signer: &str, psbt: Psbt, entry: &WalletEntry, wallet: &WalletDescriptor, network: Network, source: &dyn ChainSource) {
+    if signer == "file" {
+        download_psbt(&psbt, "unsigned.psbt").expect("Could not save PSBT");
+    } else if signer == "ledger" {
+        let Some(device) = connected_ledger() else {
+            println!("No Ledger was found. Please unlock your Ledger if it is plugged in.");
+            return;
+        };
+        ledger_sign_and_broadcast(device, psbt, entry, wallet, network, source).await;
+    } else {
+        let warn = "Unrecognized command".bright_yellow();
+        println!("{warn}: {}", signer);
+    }
+}
+
+fn print_balance(bal: u64) {
     let btc_balace = bal as f64 / 100_000_000.;
     println!("The total value for the configured wallet is {} Satoshis, equal to {} Bitcoin\nSome transactions may be unconfirmed\n", bal.to_string().bright_blue(), btc_balace.to_string().bright_blue());
 }
 
-fn print_history(txs: Vec<UserTransaction>) -> () {
+fn print_history(txs: Vec<UserTransaction>) {
     for tx in txs.iter() {
         println!("\n");
         let sent = if tx.was_sent { "Sent".bright_blue() } else { "Recieved".bright_green()};
@@ -179,52 +551,122 @@ fn print_history(txs: Vec<UserTransaction>) -> () {
 
 #[tokio::main]
 async fn main() {
-    let cfg: WalletConfig = confy::load("coinline", None).expect("Could not find configuration file");
+    let mut cfg: WalletConfig = confy::load("coinline", None).expect("Could not find configuration file");
+    if cfg.descriptor.is_empty() && !cfg.xpub.is_empty() {
+        cfg.descriptor = single_sig_descriptor(&cfg.fp, &cfg.xpub, cfg.network).unwrap_or_default();
+    }
+    if cfg.wallets.is_empty() && !cfg.descriptor.is_empty() {
+        cfg.wallets.insert("default".into(), WalletEntry { descriptor: cfg.descriptor.clone(), device: "".into(), gap: cfg.gap, hmac: cfg.hmac, frozen: Vec::new() });
+        cfg.current = "default".into();
+    }
     let args = CoinlineArgs::parse();
+    let name = match &args.wallet {
+        Some(name) => name.to_string(),
+        None if cfg.current.is_empty() => "default".to_string(),
+        None => cfg.current.to_string(),
+    };
 
     match &args.command {
         Commands::Balance(Balance { gap }) => {
-            if cfg.xpub == "" {
-                println!("\nPlease configure a wallet by using the 'set' or 'import' command.");
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            println!("\n");
+            let source = chain_source(&cfg);
+            let cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, gap.unwrap_or(entry.gap));
+            print_balance(get_balance(&cache));
+        },
+        Commands::Set(Set { fingerprint, xpub, taproot }) => {
+            if is_valid_fp(fingerprint) && is_valid_xpub(xpub, cfg.network) {
+                let descriptor = if *taproot {
+                    taproot_descriptor(fingerprint, xpub, cfg.network)
+                } else {
+                    single_sig_descriptor(fingerprint, xpub, cfg.network)
+                }.expect("Could not form a descriptor");
+                save_descriptor(cfg, &name, "other", descriptor, [0; 32]);
                 return;
             }
-            let xpub = cfg.xpub.as_str();
-            match gap {
-                Some(gap) => {
-                    println!("\n");
-                    let balance = get_balance(xpub, *gap, &cfg.client.to_string()).expect("Global Error Fetching Balance");
-                    print_balance(balance);
+            println!("Either the Fingerprint or XPUB could not be saved. Is the XPUB for the {} network?", cfg.network);
+        },
+        Commands::SetDescriptor(SetDescriptor { descriptor }) => {
+            save_descriptor(cfg, &name, "other", descriptor.to_string(), [0; 32]);
+        },
+        Commands::SetMultisig(SetMultisig { threshold, cosigners }) => {
+            let mut keys = Vec::new();
+            for cosigner in cosigners {
+                if cosigner != "ledger" {
+                    keys.push(cosigner.to_string());
+                    continue;
+                }
+                let api = HidApi::new().unwrap();
+                let Some(detected) = Ledger::<TransportHID>::enumerate(&api).next() else {
+                    println!("No Ledger was found. Please unlock your Ledger if it is plugged in.");
+                    return;
+                };
+                let device = Ledger::<TransportHID>::connect(&api, detected).expect("Could not connect to Ledger");
+                let path = DerivationPath::from_str(&multisig_account_path(cfg.network)).expect("Bad derivation");
+                let xpub = device.get_extended_pubkey(&path).await.expect("Extended Public Key not found");
+                let fingerprint = device.get_master_fingerprint().await.expect("Could not get the Fingerprint");
+                keys.push(format!("{}:{}", fingerprint, xpub));
+            }
+            match multisig_descriptor(*threshold, &keys, cfg.network) {
+                Ok(descriptor) => {
+                    save_descriptor(cfg, &name, "multisig", descriptor, [0; 32]);
+                    println!("If a Ledger is one of the cosigners, use the 'register-ledger' command before sending.\n");
                 },
-                None => {
-                    let balance = get_balance(xpub, cfg.gap, &cfg.client.to_string()).expect("Global Error Fetching Balance");
-                    print_balance(balance);
+                Err(e) => {
+                    let warn = format!("The multisig wallet could not be saved: {e}").bright_yellow();
+                    println!("\n{warn}\n");
                 },
             }
         },
-        Commands::Set(Set { fingerprint, xpub}) => {
-            if is_valid_fp(fingerprint.into()) && is_valid_xpub(xpub) {
-                let address = compute_address(xpub, true, 0).expect("Could not get address.");
-                let confirmation = address.to_string().to_string().bright_green();
-                println!("Please confirm that this is your first address [receiving]: {confirmation}\n");
-                confy::store("coinline", None, WalletConfig { gap: cfg.gap, client: cfg.client, fp: fingerprint.into(), xpub: xpub.into(), hmac: cfg.hmac }).expect("save configuration error");
-                let confirm = "Your wallet was saved".bright_green();
-                println!("{confirm}\n");
+        Commands::RegisterLedger => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let api = HidApi::new().unwrap();
+            let Some(detected) = Ledger::<TransportHID>::enumerate(&api).next() else {
+                println!("No Ledger was found. Please unlock your Ledger if it is plugged in.");
                 return;
+            };
+            let device = Ledger::<TransportHID>::connect(&api, detected).expect("Could not connect to Ledger");
+            println!("\nPlease check your Ledger\n");
+            match device.register_wallet("Coinline", &wallet.ledger_policy()).await {
+                Ok(Some(hmac)) => {
+                    let mut cfg = cfg;
+                    cfg.wallets.insert(name.clone(), WalletEntry { hmac, ..entry });
+                    confy::store("coinline", None, cfg).expect("save configuration error");
+                    let confirm = "Your wallet was registered".bright_green();
+                    println!("\n{confirm}\n");
+                },
+                _ => {
+                    let hmac_err = "There was an error in extracting the Ledger HMAC".bright_yellow();
+                    println!("{hmac_err}");
+                },
+            }
+        },
+        Commands::Combine(Combine { files }) => {
+            let psbts = files.iter().map(read_psbt).collect();
+            let psbt = match combine_psbts(psbts) {
+                Ok(psbt) => psbt,
+                Err(e) => {
+                    let warn = format!("The PSBTs could not be combined: {e}").bright_yellow();
+                    println!("\n{warn}\n");
+                    return;
+                },
+            };
+            if let Some((entry, wallet)) = configured_wallet(&cfg, &name) {
+                print_signatures(&psbt, &wallet);
+            }
+            download_psbt(&psbt, "combined.psbt").expect("Could not save PSBT");
+            if is_fully_signed(&psbt) {
+                println!("The transaction has enough signatures. Use the 'broadcast' command to send it.\n");
             }
-            println!("Either the Fingerprint or XPUB could not be saved.");
         },
         Commands::SetFile(SetFile { device, file }) => {
             if device.eq("coldcard") {
-                let (fp, xpub) = import_coldcard_from_json(file.to_path_buf()).expect("File import failure");
-                confy::store("coinline", None, WalletConfig { gap: cfg.gap, client: cfg.client, fp: fp.to_string().into(), xpub: xpub.to_string().into(), hmac: cfg.hmac }).expect("save configuration error");
-                let confirm = "Your wallet was saved".bright_green();
-                println!("{confirm}\n");
+                let descriptor = import_coldcard_from_json(file.to_path_buf(), cfg.network).expect("File import failure");
+                save_descriptor(cfg, &name, "coldcard", descriptor, [0; 32]);
                 return;
             } else if device.eq("keystone") {
-                let (fp, xpub) = import_keystone_from_txt(file.to_path_buf()).expect("File import failure");
-                confy::store("coinline", None, WalletConfig { gap: cfg.gap, client: cfg.client, fp: fp.to_string().into(), xpub: xpub.to_string().into(), hmac: cfg.hmac }).expect("save configuration error");
-                let confirm = "Your wallet was saved".bright_green();
-                println!("{confirm}\n");
+                let descriptor = import_keystone_from_txt(file.to_path_buf(), cfg.network).expect("File import failure");
+                save_descriptor(cfg, &name, "keystone", descriptor, [0; 32]);
                 return;
             } else {
                 println!("Device unregonized");
@@ -232,16 +674,19 @@ async fn main() {
             }
             
         },
-        Commands::Receive => {
-            if cfg.xpub == "" {
-                println!("\nPlease configure a wallet by using the 'set' or 'import' command.");
-                return;
-            }
-            let address = get_fresh(&cfg.xpub, &cfg.client.to_string()).expect("Global Error Fetching The Receive Address");
+        Commands::Receive(Receive { amount, label, message }) => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let source = chain_source(&cfg);
+            let mut cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
+            let address = get_fresh(&wallet, &mut cache).expect("Global Error Fetching The Receive Address");
             let address_string = address.to_string().bright_green();
             println!("\nYour next unused receiving address is: {address_string}\n");
+            let uri = payment_uri(&address, *amount, label.as_deref(), message.as_deref());
+            if amount.is_some() || label.is_some() || message.is_some() {
+                println!("The payment URI is: {}\n", uri.bright_green());
+            }
             println!("Scan the QR code below to send coins to this address\n");
-            let qr_code = QrCode::new(address.to_qr_uri()).unwrap();
+            let qr_code = QrCode::new(uri).unwrap();
             let qr_string = qr_code.render()
                                 .quiet_zone(false)
                                 .min_dimensions(40, 40)
@@ -254,102 +699,182 @@ async fn main() {
 
         },
         Commands::History(History { gap }) => {
-            if cfg.xpub == "" {
-                println!("\nPlease configure a wallet by using the 'set' or 'set-ledger' command.");
-                return;
-            }
-            match gap {
-                Some(gap) => {
-                    let hist = get_tx_history(&cfg.xpub, *gap, &cfg.client.to_string()).expect("Global Error Fetching Balance");
-                    print_history(hist);
-                }, 
-                None => {
-                    let hist = get_tx_history(&cfg.xpub, cfg.gap, &cfg.client.to_string()).expect("Global Error Fetching Balance");
-                    print_history(hist);
-                },
-            }
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let source = chain_source(&cfg);
+            let cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, gap.unwrap_or(entry.gap));
+            let hist = get_tx_history(&cache).expect("Global Error Fetching History");
+            print_history(hist);
         },
-        Commands::Send(Send { signer, receiving, value, blocks, algorithm}) => {
-            if cfg.xpub == "" {
-                println!("\nPlease configure a wallet by using the 'set' or 'set-ledger' command.");
-                return;
+        Commands::Rescan => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+            sync(&wallet, entry.gap, chain_source(&cfg).as_ref(), &mut cache).expect("Global Error Syncing The Wallet");
+            cache.save(&name, cfg.network).expect("Could not save the wallet cache");
+            let confirm = format!("Found {} transactions and {} unspent outputs", cache.transactions.len(), cache.utxos.len()).bright_green();
+            println!("\n{confirm}\n");
+        },
+        Commands::Send(Send { signer, receiving, value, blocks, algorithm, to, batch, include, exclude, locktime, sequence }) => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let mut request = PaymentRequest { address: receiving.clone(), amount: None, label: None, message: None };
+            if is_payment_uri(receiving) {
+                request = match parse_payment_uri(receiving) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        println!("\nThe payment URI could not be used: {e}\n");
+                        return;
+                    },
+                };
+                if let Some(label) = &request.label {
+                    println!("\nPaying {}", label.bright_blue());
+                }
+                if let Some(message) = &request.message {
+                    println!("{message}");
+                }
             }
-            let mut clean_wallet = false;
-            match algorithm {
-                Some(algorithm) => {
-                    if algorithm == "smallest" {
-                        clean_wallet = true;
-                    } else if algorithm == "largest" {
-                        clean_wallet = false;
-                    } else {
-                        println!("Unrecognized coin selection algorithm")
-                    }
+            let (value, blocks, algorithm) = match send_positionals(request.amount, *value, blocks.as_deref(), algorithm.as_deref()) {
+                Ok(positionals) => positionals,
+                Err(e) => {
+                    println!("\n{e}\n");
+                    return;
+                },
+            };
+            let value = match (request.amount, value) {
+                (Some(requested), Some(value)) if value != SendValue::Sats(requested) => {
+                    println!("\nThe payment URI asks for {requested} Satoshis, which does not match the value given. Leave the value out to pay the URI\n");
+                    return;
                 },
-                None => {
-                    clean_wallet = false;
+                (Some(requested), _) => Some(requested),
+                (None, Some(SendValue::Sats(value))) => Some(value),
+                (None, Some(SendValue::Max)) => None,
+                (None, None) => {
+                    println!("\nPlease give the value to send, in Satoshis or \"max\"\n");
+                    return;
                 },
+            };
+            let algorithm = algorithm.as_deref().unwrap_or("largest");
+            if !ALGORITHMS.contains(&algorithm) {
+                println!("\nUnrecognized coin selection algorithm. Options are [{}]\n", ALGORITHMS.join(", "));
+                return;
+            }
+            let mut recipients = vec![Recipient { address: request.address.clone(), amount: value }];
+            for payment in to {
+                let Some((address, amount)) = payment.rsplit_once(':').and_then(|(address, amount)| Some((address, amount.parse::<u64>().ok()?))) else {
+                    println!("\nPayments are given as [address]:[amount], not {payment}\n");
+                    return;
+                };
+                recipients.push(Recipient { address: address.into(), amount: Some(amount) });
+            }
+            let coin_control = send_coin_control(include, exclude, &entry.frozen);
+            let options = TxOptions { lock_time: locktime.map(LockTime::from_consensus), sequence: sequence.map(Sequence::from_height) };
+            if let Some(batch) = batch {
+                match import_recipients(batch.clone(), cfg.network) {
+                    Ok(batch) => recipients.extend(batch),
+                    Err(e) => {
+                        println!("\nCould not read the payments: {e}\n");
+                        return;
+                    },
+                }
             }
+            let source = chain_source(&cfg);
+            let mut cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
             if signer == "file" {
-                let res = make_and_download_transaction(*value, &cfg.xpub, &cfg.fp, receiving, *blocks as usize, clean_wallet, &cfg.client.to_string());
+                let res = make_and_download_transaction(&recipients, &coin_control, &options, &wallet, &mut cache, blocks as usize, algorithm, source.as_ref());
                 match res {
                     Ok(_) => {
                         return;
                     },
-                    Err(_) => {
-                        let warn = "An error occured. Do you have enough coins for this transaction?".bright_yellow();
-                        println!("{warn}");
+                    Err(e) => {
+                        let warn = format!("Could not make the transaction: {e}").bright_yellow();
+                        println!("\n{warn}\n");
                     },
                 }
             } else if signer == "ledger" {
-                let api = HidApi::new().unwrap();
-                for detected in Ledger::<TransportHID>::enumerate(&api) {
-                    if let Ok(device) = Ledger::<TransportHID>::connect(&api, detected) {
-                        let mut psbt = make_and_send_to_ledger(*value, &cfg.xpub, &cfg.fp, receiving, *blocks as usize, clean_wallet, &cfg.client.to_string()).expect("Error forming transaction");
-                        let pol = format!("wpkh([{}/84'/0'/0']{}/**)", &cfg.fp, &cfg.xpub);
-                        if cfg.hmac.eq(&[0; 32]) {
-                            println!("HMAC retrieval error");
-                            return;
-                        }
-                        let ok = "OK".bright_green();
-                        println!("\nPlease check your Ledger");
-                        println!("If you do not use Ledger Live, you make get an unverified inputs message. This is {ok}\n");
-                        let hmac = Some(cfg.hmac);
-                        let res = device.with_wallet("Coinline", &pol, hmac).unwrap().sign_tx(&mut psbt).await;
-                        match res {
-                            Ok(res) => { println!("\nYour transaction has been signed by your Ledger\n") },
-                            Err(_) => {
-                                println!("\nYour transaction was not signed by your device. Exiting...\n");
-                                return;
-                            }
-                        }
-                        print_psbt(psbt.clone()).expect("Could not print PSBT");
-                        if let Ok(approved) = get_user_approval() {
-                            if approved {
-                                extract_broadcast(psbt, &cfg.client.to_string()).expect("Finalization error");
-                            } else {
-                                let deny = "Broadcast not approved. Exiting...".bright_yellow();
-                                println!("\n{deny}\n");
-                                return;
-                            }
-                        } 
-
-                    }
+                let Some(device) = connected_ledger() else {
+                    println!("No Ledger was found. Please unlock your Ledger if it is plugged in.");
+                    return;
+                };
+                match make_and_send_to_ledger(&recipients, &coin_control, &options, &wallet, &mut cache, blocks as usize, algorithm, source.as_ref()) {
+                    Ok(psbt) => ledger_sign_and_broadcast(device, psbt, &entry, &wallet, cfg.network, source.as_ref()).await,
+                    Err(e) => {
+                        let warn = format!("Could not make the transaction: {e}").bright_yellow();
+                        println!("\n{warn}\n");
+                    },
                 }
             } else {
                 let warn = "Unrecognized command".bright_yellow();
                 println!("{warn}: {}", signer);
             }
         },
+        Commands::Bump(Bump { signer, txid, blocks }) => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let source = chain_source(&cfg);
+            let mut cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
+            match make_bump(*txid, &wallet, &mut cache, *blocks as usize, &entry.frozen, source.as_ref()) {
+                Ok(psbt) => sign_psbt(signer, psbt, &entry, &wallet, cfg.network, source.as_ref()).await,
+                Err(e) => {
+                    let warn = format!("Could not replace the transaction: {e}").bright_yellow();
+                    println!("\n{warn}\n");
+                },
+            }
+        },
+        Commands::Cancel(Bump { signer, txid, blocks }) => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let source = chain_source(&cfg);
+            let mut cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
+            match make_cancel(*txid, &wallet, &mut cache, *blocks as usize, source.as_ref()) {
+                Ok(psbt) => sign_psbt(signer, psbt, &entry, &wallet, cfg.network, source.as_ref()).await,
+                Err(e) => {
+                    let warn = format!("Could not cancel the transaction: {e}").bright_yellow();
+                    println!("\n{warn}\n");
+                },
+            }
+        },
+        Commands::Consolidate(Consolidate { signer, fee_rate, below, future }) => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            if *fee_rate < 1. {
+                println!("\nThe fee rate must be at least 1 sat/vB\n");
+                return;
+            }
+            let source = chain_source(&cfg);
+            let mut cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
+            let future = match future {
+                Some(future) => *future,
+                None => get_fee_estimate(6, source.as_ref()).expect("Error fetching the fee estimate"),
+            };
+            match make_consolidation(&wallet, &mut cache, *fee_rate, future, *below, &entry.frozen, source.as_ref()) {
+                Ok(psbt) => sign_psbt(signer, psbt, &entry, &wallet, cfg.network, source.as_ref()).await,
+                Err(e) => {
+                    let warn = format!("Could not consolidate: {e}").bright_yellow();
+                    println!("\n{warn}\n");
+                },
+            }
+        },
+        Commands::Cpfp(Bump { signer, txid, blocks }) => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let source = chain_source(&cfg);
+            let mut cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
+            match make_cpfp(*txid, &wallet, &mut cache, *blocks as usize, &entry.frozen, source.as_ref()) {
+                Ok(psbt) => sign_psbt(signer, psbt, &entry, &wallet, cfg.network, source.as_ref()).await,
+                Err(e) => {
+                    let warn = format!("Could not make a child transaction: {e}").bright_yellow();
+                    println!("\n{warn}\n");
+                },
+            }
+        },
         Commands::Broadcast(Broadcast { file }) => {
-            let mut file = File::open(file).expect("Could not find that PSBT");
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer).expect("Error reading that PSBT");
-            let psbt = Psbt::deserialize(&buffer).expect("Error deserializing that PSBT");
+            let psbt = read_psbt(file);
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            if !is_fully_signed(&psbt) {
+                print_signatures(&psbt, &wallet);
+                let warn = "This transaction does not have enough signatures to be broadcast.".bright_yellow();
+                println!("\n{warn}\n");
+                return;
+            }
             println!("Please approve your transaction...\n");
-            print_psbt(psbt.clone()).expect("Printing failure");
+            print_psbt(psbt.clone(), &wallet, cfg.network).expect("Printing failure");
             if let Ok(approved) = get_user_approval() {
                 if approved {
-                    extract_broadcast(psbt.clone(), &cfg.client.to_string()).expect("Finalization error");
+                    extract_broadcast(psbt.clone(), chain_source(&cfg).as_ref(), cfg.network).expect("Finalization error");
                 } else {
                     let deny = "Broadcast not approved. Exiting...".bright_yellow();
                     println!("\n{deny}\n");
@@ -361,102 +886,188 @@ async fn main() {
             }
         },
         Commands::Get => {
-            println!("\nMaster fingerprint: {:?}\n", cfg.fp);
-            println!("Extended public key: {:?}\n", cfg.xpub);
-            println!("Default gap: {}\n", cfg.gap);
-            println!("Electrum client: {}\n", cfg.client);
+            println!("\nWallet: {}\n", name);
+            if let Some(entry) = cfg.wallets.get(&name) {
+                println!("Descriptor: {:?}\n", entry.descriptor);
+                println!("Device: {}\n", entry.device);
+                println!("Default gap: {}\n", entry.gap);
+            }
+            println!("Server: {} ({})\n", cfg.client, cfg.backend);
+            for fallback in &cfg.fallbacks {
+                println!("Fallback: {}\n", fallback);
+            }
+            for (server, fingerprint) in &cfg.certificates {
+                println!("Trusted certificate for {}: {}\n", server, fingerprint);
+            }
+            if !cfg.proxy.is_empty() {
+                println!("Proxy: {}\n", cfg.proxy);
+            }
+            println!("Network: {}\n", cfg.network);
         },
         Commands::Gap(Gap { gap }) => {
             let g = *gap;
-            if g < 1 && g > 50 {
+            if !(1..=50).contains(&g) {
                 println!("Invalid gap");
                 return;
             }
-            confy::store("coinline", None, WalletConfig { gap: g, client: cfg.client, fp: cfg.fp, xpub: cfg.xpub, hmac: cfg.hmac }).expect("save configuration error");
+            let mut cfg = cfg;
+            let Some(entry) = cfg.wallets.get_mut(&name) else {
+                println!("\nThere is no wallet named {}. Use the 'wallets' command to list them.", name.bright_yellow());
+                return;
+            };
+            entry.gap = g;
+            confy::store("coinline", None, cfg).expect("save configuration error");
             let confirm = "\nYour wallet preferences were saved".bright_green();
             println!("{confirm}\n");
             return;
         },
-        Commands::Client(Client { client }) => {
-            let c = electrum_client::Client::new(client);
-            match c {
-                Ok(c) => {
-                    confy::store("coinline", None, WalletConfig { gap: cfg.gap, client: client.to_string().into(), fp: cfg.fp, xpub: cfg.xpub, hmac: cfg.hmac }).expect("save configuration error");
+        Commands::Client(Client { client, backend, auth, fallbacks, trust }) => {
+            let settings = ServerSettings { backend: backend.to_string(), auth: auth.clone().unwrap_or_default(), ..server_settings(&cfg) };
+            let mut certificates = settings.certificates.clone();
+            for server in [client].into_iter().chain(fallbacks) {
+                if *trust {
+                    match certificate_fingerprint(server, &settings.proxy) {
+                        Ok(fingerprint) => {
+                            println!("\nTrusting the certificate of {} with the SHA256 fingerprint {}", server, fingerprint.bright_green());
+                            certificates.insert(server.to_string(), fingerprint);
+                        },
+                        Err(e) => {
+                            let highlighted = server.bright_yellow();
+                            println!("Could not get the certificate of {highlighted}! {e}");
+                            return;
+                        },
+                    }
+                }
+                let settings = ServerSettings { certificates: certificates.clone(), ..settings.clone() };
+                if let Err(e) = connect(server, &settings).and_then(|source| source.tip()) {
+                    let highlighted = server.bright_yellow();
+                    println!("Could not connect to {highlighted}! {e}");
+                    return;
+                }
+            }
+            confy::store("coinline", None, WalletConfig { client: client.to_string(), fallbacks: fallbacks.clone(), backend: settings.backend, auth: settings.auth, certificates, ..cfg }).expect("save configuration error");
+            let confirm = "\nYour wallet preferences were saved".bright_green();
+            println!("{confirm}\n");
+        },
+        Commands::Proxy(Proxy { proxy }) => {
+            // stored as [host]:[port], the form every connection expects
+            let proxy = if proxy == "none" { "".to_string() } else { proxy.trim_start_matches("socks5h://").trim_start_matches("socks5://").to_string() };
+            let settings = ServerSettings { proxy: proxy.clone(), ..server_settings(&cfg) };
+            match connect(&cfg.client, &settings).and_then(|source| source.tip()) {
+                Ok(_) => {
+                    confy::store("coinline", None, WalletConfig { proxy, ..cfg }).expect("save configuration error");
                     let confirm = "\nYour wallet preferences were saved".bright_green();
                     println!("{confirm}\n");
-                    return;
                 },
-                Err(_) => {
-                    let highlighted = client.bright_yellow();
-                    println!("Could not connect to {highlighted}!");
-                    return;
-                }, 
+                Err(e) => {
+                    let highlighted = cfg.client.bright_yellow();
+                    println!("Could not connect to {highlighted} through the proxy! {e}");
+                },
             }
         },
         Commands::Fees => {
-            let fees = get_all_fee_estimates(&cfg.client.to_string()).expect("Could not fetch fees");
-            let mut block = 1;
+            let fees = get_all_fee_estimates(chain_source(&cfg).as_ref()).expect("Could not fetch fees");
             println!("\n");
-            for fee in fees {
-                let block_color = block.to_string().bright_blue();
+            for (i, fee) in fees.iter().enumerate() {
+                let block_color = (i + 1).to_string().bright_blue();
                 let fee_color = fee.to_string().bright_blue();
                 println!("\nEstimated confirmation in {} blocks is around {} Satoshis per Kilobyte", block_color, fee_color);
-                block+=1;
             }
             println!("\n");
         },
         Commands::Dust(Dust { dust }) => {
-            match dust {
-                Some(dust) => {
-                    let d = *dust;
-                    if d > 499 && d < 10001 {
-                        let utxos = get_all_utxo(&cfg.xpub, cfg.gap, &cfg.client).expect("Error Fetching UTXOs");
-                        let mut dust = 0;
-                        for utxo in utxos {
-                            if utxo.value < d as f64 {
-                                dust += 1;
-                                let warn = format!("Found a small UTXO with a value of {}", utxo.value).bright_yellow();
-                                println!("{warn}\n")
-                            }
-                        }
-                        if dust > 0 {
-                            println!("\nManage your small UTXOs by including them in your transactions\n")
-                        } else {
-                            let warn = "No dust found!".bright_green();
-                            println!("{warn}\n")
-                        }
-                        return;
-                    } else {
-                        println!("\nInvalid argument\n");
-                        return;
-                    }
-                },
-                None => {
-                    let utxos = get_all_utxo(&cfg.xpub, cfg.gap, &cfg.client).expect("Error Fetching UTXOs");
-                    let mut dust = 0;
-                    for utxo in utxos {
-                        if utxo.value < 10000 as f64 {
-                            dust += 1;
-                            let warn = format!("Found a small UTXO with a value of {}", utxo.value).bright_yellow();
-                            println!("{warn}\n")
-                        }
-                    }
-                    if dust > 0 {
-                        println!("\nManage your small UTXOs by including them in your transactions\n")
-                    } else {
-                        let warn = "No dust found!".bright_green();
-                        println!("{warn}\n")
-                    }
-                    return;
-                },
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let d = dust.unwrap_or(10000);
+            if !(500..10001).contains(&d) {
+                println!("\nInvalid argument\n");
+                return;
+            }
+            let source = chain_source(&cfg);
+            let cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
+            let utxos = get_all_utxo(&wallet, &cache).expect("Error Fetching UTXOs");
+            let mut found = Vec::new();
+            for utxo in find_dust(&utxos, d as u64, &entry.frozen) {
+                let warn = format!("Found a small UTXO with a value of {} at {}", utxo.value, utxo.outpoint()).bright_yellow();
+                println!("{warn}\n");
+                found.push(utxo.outpoint());
+            }
+            if found.is_empty() {
+                let warn = "No dust found!".bright_green();
+                println!("{warn}\n");
+                return;
+            }
+            println!("\nManage your small UTXOs by merging them with the 'consolidate' command, or freeze them so they are never spent.");
+            println!("Freeze the {} small UTXOs?", found.len());
+            if let Ok(true) = get_user_approval() {
+                let mut cfg = cfg;
+                if let Some(entry) = cfg.wallets.get_mut(&name) {
+                    entry.frozen.extend(found);
+                }
+                confy::store("coinline", None, cfg).expect("save configuration error");
+                let confirm = "\nThe small UTXOs were frozen".bright_green();
+                println!("{confirm}\n");
+            }
+        },
+        Commands::Freeze(Outpoints { outpoints }) | Commands::Unfreeze(Outpoints { outpoints }) => {
+            let freeze = matches!(args.command, Commands::Freeze(_));
+            let mut cfg = cfg;
+            let Some(entry) = cfg.wallets.get_mut(&name) else {
+                println!("\nThere is no wallet named {}. Use the 'wallets' command to list them.", name.bright_yellow());
+                return;
+            };
+            for outpoint in outpoints {
+                entry.frozen.retain(|frozen| frozen != outpoint);
+                if freeze {
+                    entry.frozen.push(*outpoint);
+                }
+            }
+            confy::store("coinline", None, cfg).expect("save configuration error");
+            let confirm = if freeze { "\nThe coins were frozen" } else { "\nThe coins were unfrozen" }.bright_green();
+            println!("{confirm}\n");
+        },
+        Commands::Frozen => {
+            let Some(entry) = cfg.wallets.get(&name) else {
+                println!("\nThere is no wallet named {}. Use the 'wallets' command to list them.", name.bright_yellow());
+                return;
+            };
+            if entry.frozen.is_empty() {
+                println!("\nNo coins are frozen\n");
+                return;
+            }
+            for outpoint in &entry.frozen {
+                println!("\n{}", outpoint.to_string().bright_blue());
+            }
+            println!("\n");
+        },
+        Commands::Utxos => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
+            let source = chain_source(&cfg);
+            let cache = synced_cache(&cfg, source.as_ref(), &name, &wallet, entry.gap);
+            if cache.utxos.is_empty() {
+                println!("\nThis wallet has no unspent outputs\n");
+                return;
+            }
+            for utxo in &cache.utxos {
+                let outpoint = utxo.outpoint.to_string().bright_blue();
+                let btc_amount = utxo.value as f64 / 100_000_000.;
+                let address = Address::from_script(&utxo.script, cfg.network).map(|address| address.to_string()).unwrap_or_default().bright_green();
+                let confirmations = if utxo.height > 0 { cache.tip.saturating_sub(utxo.height as u32) + 1 } else { 0 };
+                println!("\n{outpoint}");
+                println!("{} Satoshis [{} Bitcoin] at {address}", utxo.value, btc_amount);
+                println!("Path {}, with {} confirmations", wallet.display_path(utxo.external, utxo.index), confirmations);
+                if entry.frozen.contains(&utxo.outpoint) {
+                    let frozen = "Frozen".bright_yellow();
+                    println!("{frozen}");
+                }
             }
+            println!("\n");
         },
         Commands::Addresses => {
+            let Some((entry, wallet)) = configured_wallet(&cfg, &name) else { return; };
             for i in [true, false] {
                 for j in 0..10 {
-                    let addr = compute_address(&cfg.xpub, i, j).expect("");
-                    let is_external = if i { 0 } else { 1 };
-                    let path = format!("m/84h/0h/0h/{}/{}", is_external.to_string().bright_green(), j.to_string().bright_green());
+                    let addr = compute_address(&wallet, i, j).expect("");
+                    let path = wallet.display_path(i, j).bright_green();
                     let colored = addr.to_string().bright_green();
                     println!("\nAddress at {}: {colored}\n", path);
                 }
@@ -464,7 +1075,7 @@ async fn main() {
         },
         Commands::Ledger => {
             let api = HidApi::new().unwrap();
-            for detected in Ledger::<TransportHID>::enumerate(&api) {
+            if let Some(detected) = Ledger::<TransportHID>::enumerate(&api).next() {
                 let sn = detected.product_string();
                 let device = Ledger::<TransportHID>::connect(&api, detected).expect("Could not connect to Ledger");
                 let confirm = "Your Ledger is connected".bright_green();
@@ -477,23 +1088,26 @@ async fn main() {
             }
             println!("No Ledger was found. Please unlock your Ledger if it is plugged in.")
         },
-        Commands::SetLedger => {
+        Commands::SetLedger(SetLedger { taproot }) => {
             let api = HidApi::new().unwrap();
             for detected in Ledger::<TransportHID>::enumerate(&api) {
                 if let Ok(device) = Ledger::<TransportHID>::connect(&api, detected) {
-                    let path = DerivationPath::from_str("m/84h/0h/0h").expect("Bad derivation");
+                    let account = if *taproot { taproot_account_path(cfg.network) } else { account_path(cfg.network) };
+                    let path = DerivationPath::from_str(&account).expect("Bad derivation");
                     let xpub = device.get_extended_pubkey(&path).await.expect("Extended Public Key not found");
                     let fingerprint = device.get_master_fingerprint().await.expect("Could not get the Fingerprint");
-                    let address = compute_address(xpub.to_string().as_str(), true, 0).expect("Could not get address.");
-                    let confirmation = address.to_string().to_string().bright_green();
-                    let pol = format!("wpkh([{}/84'/0'/0']{}/**)", fingerprint.to_string(), xpub.to_string());
+                    let descriptor = if *taproot {
+                        taproot_descriptor(&fingerprint.to_string(), &xpub.to_string(), cfg.network)
+                    } else {
+                        single_sig_descriptor(&fingerprint.to_string(), &xpub.to_string(), cfg.network)
+                    }.expect("Could not form a descriptor");
+                    let wallet = WalletDescriptor::new(&descriptor, cfg.network).expect("Could not read the descriptor");
+                    let pol = wallet.ledger_policy();
                     println!("\nPlease check your Ledger\n");
                     let hmac = device.register_wallet("Coinline", &pol).await.unwrap();
                     match hmac {
                         Some(hmac) => {
-                            confy::store("coinline", None, WalletConfig { gap: cfg.gap, client: cfg.client, fp: fingerprint.to_string().into(), xpub: xpub.to_string().into(), hmac }).expect("save configuration error");
-                            let confirm = "Your wallet was saved".bright_green();
-                            println!("\n{confirm}\n");
+                            save_descriptor(cfg, &name, "ledger", descriptor, hmac);
                             return;
 
                         },
@@ -507,5 +1121,117 @@ async fn main() {
             }
             println!("No Ledger was found. Please unlock your Ledger if it is plugged in.")
         },
+        Commands::Network(NetworkArg { network }) => {
+            if *network == cfg.network {
+                println!("\nAlready using {}\n", network);
+                return;
+            }
+            // servers are network specific, so the default server follows the network
+            let client = if cfg.client == default_server(cfg.network) { default_server(*network) } else { cfg.client };
+            if !cfg.fallbacks.is_empty() {
+                println!("\nThe fallback servers were removed. Add ones for {} with the 'client' command.", network);
+            }
+            confy::store("coinline", None, WalletConfig { network: *network, client, fallbacks: Vec::new(), ..cfg }).expect("save configuration error");
+            let confirm = format!("\nNow using {}. Wallets with keys for another network cannot be used until you switch back.", network).bright_green();
+            println!("{confirm}\n");
+        },
+        Commands::Wallets => {
+            if cfg.wallets.is_empty() {
+                println!("\nNo wallets are configured.\n");
+                return;
+            }
+            println!();
+            for (wallet_name, entry) in &cfg.wallets {
+                let marker = if *wallet_name == cfg.current { "*".bright_green() } else { " ".normal() };
+                let kind = match WalletDescriptor::new(&entry.descriptor, cfg.network) {
+                    Ok(wallet) if wallet.is_multisig() => { let (m, n) = wallet.threshold(); format!("{m}-of-{n} multisig") },
+                    Ok(wallet) if wallet.is_taproot() => "taproot".to_string(),
+                    Ok(_) => "native segwit".to_string(),
+                    Err(_) => format!("not usable on {}", cfg.network),
+                };
+                let device = if entry.device.is_empty() { "unknown device" } else { entry.device.as_str() };
+                println!("{} {} [{}, {}]", marker, wallet_name.bright_blue(), kind, device);
+            }
+            println!();
+        },
+        Commands::Use(Use { name }) => {
+            if !cfg.wallets.contains_key(name) {
+                println!("\nThere is no wallet named {}. Use the 'wallets' command to list them.\n", name.bright_yellow());
+                return;
+            }
+            confy::store("coinline", None, WalletConfig { current: name.to_string(), ..cfg }).expect("save configuration error");
+            let confirm = format!("\nNow using {}", name).bright_green();
+            println!("{confirm}\n");
+        },
     }
 }
+
+#[test]
+fn test_config_serialization() {
+    let mut cfg = WalletConfig::default();
+    cfg.certificates.insert("ssl://127.0.0.1:50002".into(), "ab".repeat(32));
+    cfg.wallets.insert("main".into(), WalletEntry { descriptor: "wpkh(...)".into(), ..Default::default() });
+    cfg.current = "main".into();
+    let text = toml::to_string(&cfg).unwrap();
+    let read: WalletConfig = toml::from_str(&text).unwrap();
+    assert_eq!(read.current, "main");
+    assert_eq!(read.network, Network::Bitcoin);
+    assert_eq!(read.certificates, cfg.certificates);
+    assert_eq!(read.wallets["main"].descriptor, "wpkh(...)");
+    assert!(read.wallets["main"].frozen.is_empty());
+    // frozen coins stay frozen after the configuration is saved and loaded again
+    let frozen = vec![OutPoint::from_str("0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098:0").unwrap(), OutPoint::null()];
+    cfg.wallets.get_mut("main").unwrap().frozen = frozen.clone();
+    let read: WalletConfig = toml::from_str(&toml::to_string(&cfg).unwrap()).unwrap();
+    assert_eq!(read.wallets["main"].frozen, frozen);
+}
+
+#[test]
+fn test_frozen_coins() {
+    use coinline::wallet::actions::AddressInfo;
+    use bitcoin::ScriptBuf;
+    let address = Address::from_str("bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a").unwrap().assume_checked();
+    let txid = Txid::from_str("0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098").unwrap();
+    let utxos: Vec<SelectionUTXO> = [546, 9_999, 10_000, 600].iter().enumerate().map(|(index, value)| SelectionUTXO {
+        id: txid,
+        index,
+        value: *value,
+        script: ScriptBuf::new(),
+        info: AddressInfo { address: address.clone(), external: true, index: 0 },
+    }).collect();
+    let frozen = vec![utxos[3].outpoint()];
+    // coins below the threshold are found, except those already frozen
+    let dust: Vec<OutPoint> = find_dust(&utxos, 10_000, &frozen).iter().map(|utxo| utxo.outpoint()).collect();
+    assert_eq!(dust, vec![utxos[0].outpoint(), utxos[1].outpoint()]);
+    assert!(find_dust(&utxos, 500, &frozen).is_empty());
+    // frozen coins are kept from coin selection
+    let chosen = send_coin_control(&[], &[utxos[2].outpoint()], &frozen);
+    assert!(chosen.include.is_empty());
+    assert_eq!(chosen.exclude, vec![utxos[2].outpoint(), utxos[3].outpoint()]);
+    // but a frozen coin picked with --include is spent
+    let picked = send_coin_control(&frozen, &[], &frozen);
+    assert_eq!(picked.include, frozen);
+    assert!(picked.exclude.is_empty());
+}
+
+#[test]
+fn test_send_arguments() {
+    let parse = |args: &[&str], amount: Option<u64>| match CoinlineArgs::try_parse_from(args).unwrap().command {
+        Commands::Send(send) => send_positionals(amount, send.value, send.blocks.as_deref(), send.algorithm.as_deref()),
+        _ => panic!("not a send"),
+    };
+    let address = "bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a";
+    assert_eq!(parse(&["coinline", "send", "file", address, "5000", "6"], None), Ok((Some(SendValue::Sats(5_000)), 6, None)));
+    assert_eq!(parse(&["coinline", "send", "file", address, "max", "6", "waste"], None), Ok((Some(SendValue::Max), 6, Some("waste".to_string()))));
+    assert!(parse(&["coinline", "send", "file", address, "5000"], None).is_err());
+    assert!(parse(&["coinline", "send", "file", address, "5000", "soon"], None).is_err());
+    assert!(CoinlineArgs::try_parse_from(["coinline", "send", "file", address, "lots", "6"]).is_err());
+    // a URI with an amount leaves out the value, so the blocks come first
+    let uri = "bitcoin:bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a?amount=0.001";
+    assert_eq!(parse(&["coinline", "send", "file", uri, "6"], Some(100_000)), Ok((None, 6, None)));
+    assert_eq!(parse(&["coinline", "send", "file", uri, "6", "bnb"], Some(100_000)), Ok((None, 6, Some("bnb".to_string()))));
+    assert!(parse(&["coinline", "send", "file", uri, "1000"], Some(100_000)).is_err());
+    // or gives the value as usual
+    assert_eq!(parse(&["coinline", "send", "file", uri, "100000", "6"], Some(100_000)), Ok((Some(SendValue::Sats(100_000)), 6, None)));
+    assert_eq!(parse(&["coinline", "send", "file", uri, "100000", "6", "bnb"], Some(100_000)), Ok((Some(SendValue::Sats(100_000)), 6, Some("bnb".to_string()))));
+}
@@ -0,0 +1,161 @@
+use std::{collections::{BTreeMap, HashMap}, error::Error, fs::{self, File}, io::{self, Read, Write}, path::PathBuf};
+use bitcoin::{consensus::encode::{deserialize, serialize}, hashes::hex::FromHex, OutPoint, ScriptBuf, Transaction, Txid, Network};
+use serde::{Deserialize, Serialize};
+use crate::server::source::History;
+use crate::wallet::actions::{compute_script_pubkey, WalletDescriptor};
+
+/// Everything Coinline knows about a wallet from previous runs, saved next to the configuration file
+/// so each command only asks the server for what changed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WalletCache {
+    /// The descriptor the cache was built for. A different descriptor starts a new cache.
+    pub descriptor: String,
+    pub tip: u32,
+    /// Scripts of the derived receive addresses, in index order.
+    pub receive: Vec<ScriptBuf>,
+    /// Scripts of the derived change addresses, in index order.
+    pub change: Vec<ScriptBuf>,
+    pub last_receive: Option<u32>,
+    pub last_change: Option<u32>,
+    /// The last history of each script with the status the server gave for it, so unchanged scripts are not fetched again.
+    pub histories: BTreeMap<ScriptBuf, (String, History)>,
+    pub transactions: BTreeMap<Txid, CachedTransaction>,
+    pub utxos: Vec<CachedUtxo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedTransaction {
+    /// The Electrum convention: the block height when confirmed, 0 or below while in the mempool.
+    pub height: i32,
+    pub hex: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedUtxo {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub script: ScriptBuf,
+    pub external: bool,
+    pub index: u32,
+    pub height: i32,
+}
+
+impl WalletCache {
+    /// Loads the cache for the named wallet, starting fresh if there is none or the descriptor changed.
+    pub fn load(name: &str, wallet: &WalletDescriptor) -> Self {
+        let descriptor = wallet.descriptor.to_string();
+        let cache = cache_path(name, wallet.network).ok().and_then(|path| {
+            let mut content = String::new();
+            File::open(path).ok()?.read_to_string(&mut content).ok()?;
+            serde_json::from_str::<WalletCache>(&content).ok()
+        });
+        match cache {
+            Some(cache) if cache.descriptor == descriptor => cache,
+            _ => WalletCache { descriptor, ..Default::default() },
+        }
+    }
+
+    pub fn save(&self, name: &str, network: Network) -> Result<(), Box<dyn Error>> {
+        let path = cache_path(name, network)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(path)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// The script at an index, derived once and remembered after.
+    pub fn script(&mut self, wallet: &WalletDescriptor, external: bool, index: u32) -> Result<ScriptBuf, Box<dyn Error>> {
+        let scripts = if external { &mut self.receive } else { &mut self.change };
+        while scripts.len() <= index as usize {
+            scripts.push(compute_script_pubkey(wallet, external, scripts.len() as u32)?);
+        }
+        Ok(scripts[index as usize].clone())
+    }
+
+    pub fn last_used(&self, external: bool) -> Option<u32> {
+        if external { self.last_receive } else { self.last_change }
+    }
+
+    /// The keychain and index of each derived script.
+    pub fn owners(&self) -> HashMap<ScriptBuf, (bool, u32)> {
+        let receive = self.receive.iter().enumerate().map(|(i, script)| (script.clone(), (true, i as u32)));
+        let change = self.change.iter().enumerate().map(|(i, script)| (script.clone(), (false, i as u32)));
+        receive.chain(change).collect()
+    }
+
+    pub fn transaction(&self, txid: &Txid) -> Option<Transaction> {
+        let cached = self.transactions.get(txid)?;
+        deserialize(&Vec::<u8>::from_hex(&cached.hex).ok()?).ok()
+    }
+
+    pub fn insert_transaction(&mut self, tx: &Transaction, height: i32) {
+        let hex = serialize(tx).iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.transactions.insert(tx.txid(), CachedTransaction { height, hex });
+    }
+
+    /// Recomputes the unspent outputs from the cached transactions.
+    pub fn update_utxos(&mut self) -> Result<(), Box<dyn Error>> {
+        let owners = self.owners();
+        let mut transactions = Vec::new();
+        for (txid, cached) in &self.transactions {
+            let tx = self.transaction(txid).ok_or(io::Error::other("A cached transaction could not be read"))?;
+            transactions.push((tx, cached.height));
+        }
+        let spent: Vec<OutPoint> = transactions.iter().flat_map(|(tx, _)| tx.input.iter().map(|input| input.previous_output)).collect();
+        let mut utxos = Vec::new();
+        for (tx, height) in &transactions {
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint { txid: tx.txid(), vout: vout as u32 };
+                if let Some((external, index)) = owners.get(&output.script_pubkey) {
+                    if !spent.contains(&outpoint) {
+                        utxos.push(CachedUtxo { outpoint, value: output.value, script: output.script_pubkey.clone(), external: *external, index: *index, height: *height });
+                    }
+                }
+            }
+        }
+        self.utxos = utxos;
+        Ok(())
+    }
+}
+
+/// Where the cache for a wallet is kept: a cache folder beside the configuration file.
+pub fn cache_path(name: &str, network: Network) -> Result<PathBuf, Box<dyn Error>> {
+    let config = confy::get_configuration_file_path("coinline", None)?;
+    let dir = config.parent().ok_or(io::Error::other("The configuration folder could not be found"))?;
+    Ok(dir.join("cache").join(format!("{}-{}.json", name, network)))
+}
+
+#[test]
+fn test_cached_utxos() {
+    use bitcoin::{absolute::LockTime, Sequence, TxIn, TxOut, Witness};
+    use crate::wallet::actions::single_sig_descriptor;
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let receive = cache.script(&wallet, true, 0).unwrap();
+    let change = cache.script(&wallet, false, 0).unwrap();
+    let funding = Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn::default()],
+        output: vec![TxOut { value: 50_000, script_pubkey: receive }],
+    };
+    let spend = Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn { previous_output: OutPoint { txid: funding.txid(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }],
+        output: vec![TxOut { value: 20_000, script_pubkey: ScriptBuf::new() }, TxOut { value: 29_000, script_pubkey: change.clone() }],
+    };
+    cache.insert_transaction(&funding, 800_000);
+    cache.insert_transaction(&spend, 0);
+    cache.update_utxos().unwrap();
+    assert_eq!(cache.utxos.len(), 1);
+    assert_eq!(cache.utxos[0].outpoint, OutPoint { txid: spend.txid(), vout: 1 });
+    assert_eq!(cache.utxos[0].script, change);
+    assert!(!cache.utxos[0].external);
+    assert_eq!(cache.transaction(&spend.txid()), Some(spend));
+}
@@ -1 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod server;
+pub mod cache;
+pub mod source;
+pub mod pool;
+pub mod tls;
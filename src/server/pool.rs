@@ -0,0 +1,169 @@
+use std::{cell::RefCell, error::Error, io};
+use bitcoin::{ScriptBuf, Transaction, Txid};
+use colored::Colorize;
+use crate::server::source::{connect, ChainSource, History, ServerSettings};
+use crate::wallet::actions::WalletDescriptor;
+
+/// A primary server and its fallbacks, all of the same backend. A server is only used once it
+/// answers a health check, and any failing request moves on to the next server in the list.
+pub struct ServerPool {
+    servers: Vec<String>,
+    settings: ServerSettings,
+    active: RefCell<Option<(usize, Box<dyn ChainSource>)>>,
+    watched: RefCell<Option<(WalletDescriptor, Vec<Transaction>)>>,
+}
+
+impl ServerPool {
+    /// Connects to the first healthy server, with the primary first.
+    pub fn connect(servers: Vec<String>, settings: ServerSettings) -> Result<Self, Box<dyn Error>> {
+        let pool = ServerPool { servers, settings, active: RefCell::new(None), watched: RefCell::new(None) };
+        pool.failover(0)?;
+        Ok(pool)
+    }
+
+    /// The server requests are currently sent to.
+    pub fn active_server(&self) -> Option<String> {
+        self.active.borrow().as_ref().map(|(i, _)| self.servers[*i].clone())
+    }
+
+    fn failover(&self, start: usize) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+        for (i, server) in self.servers.iter().enumerate().skip(start) {
+            let healthy = connect(server, &self.settings).and_then(|source| {
+                source.tip()?;
+                if let Some((wallet, known)) = self.watched.borrow().as_ref() {
+                    source.watch(wallet, known)?;
+                }
+                Ok(source)
+            });
+            match healthy {
+                Ok(source) => {
+                    if i > 0 {
+                        let warn = format!("Using the fallback server {server}").bright_yellow();
+                        eprintln!("{warn}");
+                    }
+                    *self.active.borrow_mut() = Some((i, source));
+                    return Ok(());
+                },
+                Err(e) => {
+                    let warn = format!("{server} is unavailable: {e}").bright_yellow();
+                    eprintln!("{warn}");
+                    errors.push(server.clone());
+                },
+            }
+        }
+        *self.active.borrow_mut() = None;
+        Err(Box::new(io::Error::other(format!("No server could be reached. Tried {}", errors.join(", ")))))
+    }
+
+    fn call<T>(&self, request: impl Fn(&dyn ChainSource) -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+        loop {
+            let (index, result) = {
+                let active = self.active.borrow();
+                let (index, source) = active.as_ref().ok_or(io::Error::other("No server is connected"))?;
+                (*index, request(source.as_ref()))
+            };
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) if index + 1 < self.servers.len() && is_server_failure(e.as_ref()) => {
+                    let warn = format!("{} failed: {e}", self.servers[index]).bright_yellow();
+                    eprintln!("{warn}");
+                    self.failover(index + 1)?;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Whether a request failed because the server could not be reached or broke down, rather than
+/// because the server refused the request, such as a transaction paying too little fee.
+fn is_server_failure(error: &(dyn Error + 'static)) -> bool {
+    use electrum_client::Error as Electrum;
+    if let Some(error) = error.downcast_ref::<Electrum>() {
+        matches!(error, Electrum::IOError(_) | Electrum::SharedIOError(_) | Electrum::AllAttemptsErrored(_) | Electrum::CouldntLockReader | Electrum::Mpsc)
+    } else if let Some(error) = error.downcast_ref::<esplora_client::Error>() {
+        // responses with a status are reported as HttpResponse, so Ureq errors are transport errors
+        match error {
+            esplora_client::Error::Ureq(_) | esplora_client::Error::UreqTransport(_) | esplora_client::Error::Io(_) => true,
+            esplora_client::Error::HttpResponse(status) => *status >= 500,
+            _ => false,
+        }
+    } else if let Some(error) = error.downcast_ref::<bitcoincore_rpc::Error>() {
+        matches!(error, bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Transport(_)) | bitcoincore_rpc::Error::Io(_))
+    } else if let Some(error) = error.downcast_ref::<io::Error>() {
+        error.kind() != io::ErrorKind::Other
+    } else {
+        false
+    }
+}
+
+impl ChainSource for ServerPool {
+    fn watch(&self, wallet: &WalletDescriptor, known: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        self.call(|source| source.watch(wallet, known))?;
+        // a server failed over to later must watch the wallet too
+        *self.watched.borrow_mut() = Some((wallet.clone(), known.to_vec()));
+        Ok(())
+    }
+
+    fn tip(&self) -> Result<u32, Box<dyn Error>> {
+        self.call(|source| source.tip())
+    }
+
+    fn script_statuses(&self, scripts: &[ScriptBuf]) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+        self.call(|source| source.script_statuses(scripts))
+    }
+
+    fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<History>, Box<dyn Error>> {
+        self.call(|source| source.script_histories(scripts))
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        self.call(|source| source.transactions(txids))
+    }
+
+    fn mempool_fee(&self, txid: &Txid) -> Result<Option<u64>, Box<dyn Error>> {
+        self.call(|source| source.mempool_fee(txid))
+    }
+
+    fn fee_estimate(&self, blocks: usize) -> Result<f64, Box<dyn Error>> {
+        self.call(|source| source.fee_estimate(blocks))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> {
+        self.call(|source| source.broadcast(tx))
+    }
+}
+
+#[test]
+fn test_pool_failover() {
+    use std::{io::{Read, Write}, net::TcpListener, thread};
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let healthy = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 1024];
+            let read = stream.read(&mut request).unwrap();
+            // the server answers, but refuses every transaction
+            let response: &[u8] = if request[..read].starts_with(b"POST") {
+                b"HTTP/1.1 400 Bad Request\r\nContent-Length: 13\r\nConnection: close\r\n\r\nmin relay fee"
+            } else {
+                b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\n800010"
+            };
+            stream.write_all(response).unwrap();
+        }
+    });
+    // nothing listens on the discard port
+    let down = "http://127.0.0.1:9".to_string();
+    let settings = ServerSettings { backend: "esplora".into(), ..Default::default() };
+    let pool = ServerPool::connect(vec![down.clone(), healthy.clone()], settings.clone()).unwrap();
+    assert_eq!(pool.active_server(), Some(healthy.clone()));
+    assert_eq!(pool.tip().unwrap(), 800_010);
+    assert!(ServerPool::connect(vec![down.clone()], settings.clone()).is_err());
+    // a refused transaction is returned as is, without trying the fallback
+    let pool = ServerPool::connect(vec![healthy.clone(), down], settings).unwrap();
+    let tx = Transaction { version: 2, lock_time: bitcoin::absolute::LockTime::ZERO, input: vec![], output: vec![] };
+    assert!(pool.broadcast(&tx).is_err());
+    assert_eq!(pool.active_server(), Some(healthy));
+}
@@ -2,14 +2,19 @@
 extern crate electrum_client;
 extern crate bitcoin;
 use colored::*;
-use std::{error::Error, cmp::Ordering, fmt::format, sync::{Arc, Mutex}, collections::HashMap};
+use std::{error::Error, cmp::Ordering, fmt::format, sync::{Arc, Mutex}, collections::HashMap, io};
 use electrum_client::{Client,ElectrumApi, Config};
-use bitcoin::{Address, Txid, Transaction};
+use bitcoin::{Address, Txid, Transaction, Network, ScriptBuf};
 use rand::Rng;
-use crate::wallet::actions::{compute_script_pubkey, compute_address, SelectionUTXO, compute_address_info, AddressInfo};
+use crate::server::{cache::WalletCache, source::ChainSource};
+use crate::wallet::actions::{compute_script_pubkey, compute_address, SelectionUTXO, compute_address_info, AddressInfo, WalletDescriptor};
 use rayon::prelude::*;
 use indicatif::{ProgressBar, ProgressStyle, ProgressState, MultiProgress};
 
+/// Nodes do not relay transactions paying less than 1 sat/vB by default.
+const MIN_RELAY_FEE_RATE: f64 = 1.;
+/// The most scripts asked about in a single request, so long-used wallets are not queried all at once.
+const SYNC_BATCH_SIZE: u32 = 100;
 
 #[derive(Debug, serde::Serialize)]
 pub struct UserTransaction {
@@ -26,32 +31,38 @@ impl UserTransaction {
     }
 }
 
-fn select_rand_server() -> String {
-    let servers = ["ssl://electrum.blockstream.info:50002",];
-    let mut rng = rand::thread_rng();
-    let random_index = rng.gen_range(0..servers.len());
-    servers[random_index].to_string()
+/// The public Electrum server used until the user configures their own.
+pub fn default_server(network: Network) -> String {
+    match network {
+        Network::Bitcoin => "ssl://electrum.blockstream.info:50002".into(),
+        Network::Testnet => "ssl://electrum.blockstream.info:60002".into(),
+        Network::Signet => "ssl://mempool.space:60602".into(),
+        _ => "tcp://127.0.0.1:50001".into(),
+    }
+}
 
+/// Where a transaction can be viewed in a block explorer, if the network has one.
+pub fn explorer_url(id: &Txid, network: Network) -> Option<String> {
+    match network {
+        Network::Bitcoin => Some(format!("https://mempool.space/tx/{id}")),
+        Network::Testnet => Some(format!("https://mempool.space/testnet/tx/{id}")),
+        Network::Signet => Some(format!("https://mempool.space/signet/tx/{id}")),
+        _ => None,
+    }
 }
 
-pub fn get_fee_estimate(blocks: usize, client: &str) -> Result<f64, Box<dyn Error>> {
-    let client = Client::new(client)?;
-    let btc_fee = client.estimate_fee(blocks)?;
-    let fee = btc_fee * 100_000_000.0 / 1_000.0; //convert to satoshi and convert from kb to bytes
-    Ok(fee)
+/// The fee rate in sat/vB to confirm within the given blocks, never below the minimum relay fee.
+pub fn get_fee_estimate(blocks: usize, source: &dyn ChainSource) -> Result<f64, Box<dyn Error>> {
+    Ok(source.fee_estimate(blocks)?.max(MIN_RELAY_FEE_RATE))
 }
 
-pub fn get_all_fee_estimates(client: &str) -> Result<Vec<f64>, Box<dyn Error>> {
-    let client = Client::new(client)?;
-    let msg = "\nContected to an Electrum server\n".bright_green();
-    eprintln!("{msg}");
+pub fn get_all_fee_estimates(source: &dyn ChainSource) -> Result<Vec<f64>, Box<dyn Error>> {
     let mut fees = Vec::new();
-    let bar = ProgressBar::new(20 as u64);
+    let bar = ProgressBar::new(20);
     bar.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ")?);
     for i in 1..26 {
         bar.set_message(format!("Fetching fee estimates for a {} block confirmation", i));
-        let btc_fee = client.estimate_fee(i)?;
-        let fee = btc_fee * 100_000_000.0 ; //convert to satoshi
+        let fee = source.fee_estimate(i)? * 1_000.0; //convert to kilobytes
         fees.push(fee.round());
         bar.inc(1)
     }
@@ -59,226 +70,222 @@ pub fn get_all_fee_estimates(client: &str) -> Result<Vec<f64>, Box<dyn Error>> {
     Ok(fees)
 }
 
-pub fn get_fresh(xpub: &str, client: &str) -> Result<Address, Box<dyn Error>> {
-    let mut i = 0;
-    let client = Client::new(client)?;
-    let bar = ProgressBar::new(1 as u64);
-    bar.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ")?);
-    loop {
-        let script = compute_script_pubkey(xpub, true, i)?;
-        let history = client.script_get_history(&script)?;
-        bar.set_message(format!("Fetching balance from address at m/84h/0h/0h/0/{i}"));
-        if history.len() == 0 {
-            bar.inc(1);
-            bar.finish_and_clear();
-            let addr = compute_address(xpub, true, i)?;
-            break Ok(addr);
-        }
-        i+=1;
-    }
+/// The first receive address after the last one seen on chain.
+pub fn get_fresh(wallet: &WalletDescriptor, cache: &mut WalletCache) -> Result<Address, Box<dyn Error>> {
+    let index = cache.last_used(true).map_or(0, |last| last + 1);
+    cache.script(wallet, true, index)?;
+    compute_address(wallet, true, index)
 }
 
-pub fn get_fresh_change(xpub: &str, client: &str) -> Result<AddressInfo, Box<dyn Error>> {
-    let mut i = 0;
-    let client = Client::new(select_rand_server().as_str())?;
+/// The first change address after the last one seen on chain.
+pub fn get_fresh_change(wallet: &WalletDescriptor, cache: &mut WalletCache) -> Result<AddressInfo, Box<dyn Error>> {
     let message = "Your transaction is being built".bright_green();
     println!("\n{}", message);
-    print!("\nFetching a new change address...");
-    loop {
-        let script = compute_script_pubkey(xpub, false, i)?;
-        let history = client.script_get_history(&script)?;
-        if history.len() == 0 {
-            let info = compute_address_info(xpub, false, i)?;
-            break Ok(info);
-        }
-        i+=1;
-    }
+    let index = cache.last_used(false).map_or(0, |last| last + 1);
+    cache.script(wallet, false, index)?;
+    compute_address_info(wallet, false, index)
 }
 
-pub fn broadcast(tx: Transaction, client: &str) -> Result<(), Box<dyn Error>> {
-    let client = Client::new(client)?;
-    let msg = "\nContected to an Electrum server\n".bright_green();
-    eprintln!("{msg}");
-    let id = client.transaction_broadcast(&tx)?;
+pub fn broadcast(tx: Transaction, source: &dyn ChainSource, network: Network) -> Result<(), Box<dyn Error>> {
+    let id = source.broadcast(&tx)?;
     let message = "Your transaction was sent".bright_green();
     println!("{message}");
     println!("Transaction ID: {}\n", id);
-    println!("View it at https://mempool.space\n");
+    if let Some(url) = explorer_url(&id, network) {
+        println!("View it at {url}\n");
+    }
     Ok(())
 }
 
-pub fn get_balance(xpub: &str, gap: u8, client: &str) -> Result<i64, Box<dyn Error>> {
-    let client = Client::new(client)?;
-    let msg = "\nContected to an Electrum server\n".bright_green();
-    eprintln!("{msg}");
-    let m = Arc::new(Mutex::new(MultiProgress::new()));
-    let mp_clone = m.lock().unwrap();
-    let results = rayon::join(|| subaccount_balance(xpub, &client, gap, true,  &mp_clone).expect("error getting receive balance"), || subaccount_balance(xpub, &client, gap, false, &mp_clone).expect("error getting change balance"));
-    Ok(results.0 + results.1)
-}
-
-fn subaccount_balance(xpub: &str, client: &Client, gap: u8, external: bool, m: &MultiProgress) -> Result<i64, Box<dyn Error>> {
-    let mut balance = 0 as i64;
-    let mut zero_balance = 0;
-    let mut i = 0;
-    let bar = m.add(ProgressBar::new(gap as u64));
+/// Brings the cache up to date with the server. Scripts are queried in batches of at most `SYNC_BATCH_SIZE`,
+/// covering every address known to be used and then the gap, and only transactions that are not already cached are downloaded.
+/// Electrum servers report a status hash for each script, so only the histories that changed are fetched again.
+/// Esplora and Bitcoin Core have no such hash and every history is fetched on each sync.
+pub fn sync(wallet: &WalletDescriptor, gap: u8, source: &dyn ChainSource, cache: &mut WalletCache) -> Result<(), Box<dyn Error>> {
+    let known: Vec<Transaction> = cache.transactions.keys().filter_map(|txid| cache.transaction(txid)).collect();
+    source.watch(wallet, &known)?;
+    cache.tip = source.tip()?;
+    let bar = ProgressBar::new(2);
     bar.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ")?);
-    loop {
-        let script = compute_script_pubkey(xpub, external, i)?;
-        let is_internal = if external { 0 } else { 1 };
-        let address_str_path = format!("m/84h/0h/0h/{is_internal}/{i}").green();
-        bar.set_message(format!("Fetching balance from address at {}", address_str_path));
-        let history = client.script_get_balance(&script)?;
-        let address_balance = history.confirmed as i64 + history.unconfirmed;
-        balance += address_balance;
-        if address_balance == 0 {
-            bar.inc(1);
-            zero_balance += 1;
-            if zero_balance > gap {
-                bar.finish_and_clear();
-                m.remove(&bar);
-                break Ok(balance);
+    let mut heights: HashMap<Txid, i32> = HashMap::new();
+    for external in [true, false] {
+        let mut last_used = None;
+        let mut unused: u32 = 0;
+        let mut i = 0;
+        // every address known to be used and the gap after it are asked about before stopping at a gap
+        let first = cache.last_used(external).map_or(0, |last| last + 1) + gap as u32 + 1;
+        while i < first || unused <= gap as u32 {
+            let window = if i < first { first - i } else { gap as u32 + 1 }.min(SYNC_BATCH_SIZE);
+            let address_str_path = wallet.display_path(external, i).green();
+            bar.set_message(format!("Fetching history from {} addresses at {}", window, address_str_path));
+            let scripts = (i..i + window).map(|j| cache.script(wallet, external, j)).collect::<Result<Vec<ScriptBuf>, _>>()?;
+            let statuses = source.script_statuses(&scripts)?;
+            let unchanged = |script: &ScriptBuf, status: &Option<String>| {
+                status.is_some() && cache.histories.get(script).map(|(cached, _)| cached) == status.as_ref()
+            };
+            let changed: Vec<ScriptBuf> = scripts.iter().zip(&statuses).filter(|(script, status)| !unchanged(script, status)).map(|(script, _)| script.clone()).collect();
+            let mut fetched = if changed.is_empty() { Vec::new() } else { source.script_histories(&changed)? }.into_iter();
+            for (script, status) in scripts.into_iter().zip(statuses) {
+                let history = match cache.histories.get(&script) {
+                    Some((cached, history)) if status.as_ref() == Some(cached) => history.clone(),
+                    _ => {
+                        let history = fetched.next().ok_or(io::Error::other("The server returned too few histories"))?;
+                        if let Some(status) = status {
+                            cache.histories.insert(script, (status, history.clone()));
+                        }
+                        history
+                    }
+                };
+                if history.is_empty() {
+                    unused += 1;
+                } else {
+                    unused = 0;
+                    last_used = Some(i);
+                }
+                heights.extend(history);
+                i += 1;
             }
         }
-        i+=1;
+        if external { cache.last_receive = last_used } else { cache.last_change = last_used }
+        bar.inc(1);
+    }
+    // transactions dropped from the mempool or reorganized out are forgotten
+    cache.transactions.retain(|txid, _| heights.contains_key(txid));
+    let mut missing = Vec::new();
+    for (txid, height) in &heights {
+        match cache.transactions.get_mut(txid) {
+            Some(cached) => cached.height = *height,
+            None => missing.push(*txid),
+        }
+    }
+    if !missing.is_empty() {
+        bar.set_message(format!("Downloading {} transactions", missing.len()));
+        for tx in source.transactions(&missing)? {
+            cache.insert_transaction(&tx, heights[&tx.txid()]);
+        }
     }
+    cache.update_utxos()?;
+    bar.finish_and_clear();
+    Ok(())
+}
+
+pub fn get_balance(cache: &WalletCache) -> u64 {
+    cache.utxos.iter().map(|utxo| utxo.value).sum()
 }
 
-pub fn get_all_utxo(xpub: &str, gap: u8, client: &str) -> Result<Vec<SelectionUTXO>, Box<dyn Error>> {
-    let client = Client::new(client)?;
-    let msg = "\nContected to an Electrum server\n".bright_green();
-    eprintln!("{msg}");
+pub fn get_all_utxo(wallet: &WalletDescriptor, cache: &WalletCache) -> Result<Vec<SelectionUTXO>, Box<dyn Error>> {
     let mut utxos = Vec::new();
-    let bar = ProgressBar::new(gap as u64);
-    bar.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ")?);
-    for account in [true, false] {
-        let mut zero_balance = 0;
-        let mut i = 0;
-        loop {
-            let is_ext = if account { 0 } else { 1 };
-            let address_str_path = format!("m/84h/0h/0h/{is_ext}/{i}").green();
-            bar.set_message(format!("Fetching balance from address at {}", address_str_path));
-            let script = compute_script_pubkey(xpub, account, i)?;
-            let info = compute_address_info(xpub, account, i)?;
-            let utxo = client.script_list_unspent(&script)?;
-            let length = utxo.len();
-            utxos.extend(utxo.iter().map(|utxo| SelectionUTXO { id: utxo.tx_hash, index: utxo.tx_pos, value: utxo.value as f64, script: script.clone(), info: info.clone()}));
-            if length == 0 {
-                zero_balance += 1;
-                bar.inc(1);
-                if zero_balance > gap {
-                    break;
-                }
-            }
-            i+=1;
-        }   
+    for utxo in &cache.utxos {
+        let info = compute_address_info(wallet, utxo.external, utxo.index)?;
+        utxos.push(SelectionUTXO { id: utxo.outpoint.txid, index: utxo.outpoint.vout as usize, value: utxo.value, script: utxo.script.clone(), info });
     }
-    bar.finish_and_clear();
     Ok(utxos)
 }
 
-pub fn get_tx_history(xpub: &str, gap: u8, client: &str) -> Result<Vec<UserTransaction>, Box<dyn Error>>  {
+pub fn get_tx_history(cache: &WalletCache) -> Result<Vec<UserTransaction>, Box<dyn Error>>  {
+    let owners = cache.owners();
     let mut received = Vec::new();
-    let mut change = Vec::new();
-    let mut sent = Vec::new();
-    let client = Client::new(client)?;
-
-    let msg = "\nContected to an Electrum server\n".bright_green();
-    eprintln!("{msg}");
-
-    let bar = ProgressBar::new((gap * 2) as u64);
-    bar.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{wide_bar:.cyan/blue}] ")?);
-
-    for account in [true, false] {
-        let mut zero_balance = 0;
-        let mut i = 0;
-        loop {
-            let script_buf = compute_script_pubkey(xpub, account, i).expect("address formation error");
-            let history: Vec<electrum_client::GetHistoryRes> = client.script_get_history(&script_buf).expect("error fetching from script pubkey");
-            let is_internal: i32 = if account { 0 } else { 1 };
-            let address_str_path = format!("m/84h/0h/0h/{is_internal}/{i}").green();
-            bar.set_message(format!("Fetching history from address at {}", address_str_path));
-            if history.len() == 0 {
-                bar.inc(1);
-                i+=1;
-                zero_balance+=1;
-                if zero_balance > gap {
-                    break
-                }
+    for (txid, cached) in &cache.transactions {
+        let transaction = cache.transaction(txid).ok_or(io::Error::other("A cached transaction could not be read"))?;
+        let confirmed = cached.height > 0;
+        let mut change = 0;
+        for out in &transaction.output {
+            match owners.get(&out.script_pubkey) {
+                Some((true, _)) => received.push(UserTransaction::new(out.value, cached.height, false, confirmed, *txid)),
+                Some((false, _)) => change += out.value,
+                None => (),
             }
-            for tx in history {
-                let txid = tx.tx_hash;
-                let transaction = client.transaction_get(&txid)?;
-                let inputs = transaction.input;
-                let outputs = transaction.output;
-                for out in outputs {
-                    if script_buf == out.script_pubkey && account {
-                        let confirmed = tx.height > 0;
-                        received.push(UserTransaction::new(out.value, tx.height, false, confirmed, txid));
-                    } 
-                    if script_buf == out.script_pubkey && !account {
-                        change.push((txid, out.value as i64));
-                    } 
-                }
-                let mut sent_val = 0;
-                for inp in inputs {
-                    let prev_out = client.transaction_get(&inp.previous_output.txid)?;
-                    for out in prev_out.output {
-                        if script_buf == out.script_pubkey {
-                            sent_val += out.value;
-                        }
-                    } 
-                }
-                if sent_val > 0 {
-                    sent.push((txid, tx.height, sent_val as i64));
+        }
+        // the inputs we spent are outputs of transactions already in the cache
+        let mut sent_val = 0;
+        for inp in &transaction.input {
+            if let Some(prev_out) = cache.transaction(&inp.previous_output.txid) {
+                if let Some(out) = prev_out.output.get(inp.previous_output.vout as usize) {
+                    if owners.contains_key(&out.script_pubkey) {
+                        sent_val += out.value;
+                    }
                 }
             }
-            i+=1;
         }
-    }
-    bar.set_message("Collecting sent transcations");
-    let mut net_map: HashMap<&Txid, (i32, i64)> = std::collections::HashMap::new();
-    
-    // Collect values from the spent vector
-    for (txid, height, spent_value) in &sent {
-        if let Some(tx) = net_map.get(txid) {
-            net_map.insert(txid, (*height, *spent_value + tx.1));
-        } else {
-            net_map.entry(txid).or_insert_with(||  (*height, *spent_value));
-        }
-    }
-
-    // Subtract values from the change vector
-    for (txid, change_value) in &change {
-        if let Some(net_value) = net_map.get_mut(txid) {
-            net_value.1 = net_value.1 - change_value;
-        }
-    }
-
-    // Convert the HashMap back into a vector of tuples
-    let net: Vec<(&Txid, (i32, i64))> = net_map.into_iter().collect();
-    for (id,  v) in net {
-        if v.1 < 0 {
-            continue;
-        } else {
-            let confirmed = v.0 > 0;
-            received.push(UserTransaction::new(v.1 as u64, v.0, true, confirmed, *id));
+        if sent_val > 0 && sent_val >= change {
+            received.push(UserTransaction::new(sent_val - change, cached.height, true, confirmed, *txid));
         }
     }
-
-    bar.finish_with_message("Sorting the transactions");
     received.par_sort_unstable_by(|a, b| { 
         if !a.confirmed {
             Ordering::Less
         } else if !b.confirmed{
             Ordering::Greater
+        } else if a.height > b.height {
+            Ordering::Less
         } else {
-            if a.height > b.height {
-                Ordering::Less
-            } else {
-                Ordering::Greater
-            }
+            Ordering::Greater
         }
     });
     Ok(received)
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_sync_batches() {
+    use std::cell::RefCell;
+    use crate::server::source::History;
+    use crate::wallet::actions::single_sig_descriptor;
+    struct Recorder(RefCell<Vec<usize>>);
+    impl ChainSource for Recorder {
+        fn tip(&self) -> Result<u32, Box<dyn Error>> { Ok(800_000) }
+        fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<History>, Box<dyn Error>> {
+            self.0.borrow_mut().push(scripts.len());
+            Ok(vec![Vec::new(); scripts.len()])
+        }
+        fn transactions(&self, _txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> { Ok(Vec::new()) }
+        fn fee_estimate(&self, _blocks: usize) -> Result<f64, Box<dyn Error>> { Ok(1.) }
+        fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> { Ok(tx.txid()) }
+    }
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), last_receive: Some(249), ..Default::default() };
+    let source = Recorder(RefCell::new(Vec::new()));
+    sync(&wallet, 20, &source, &mut cache).unwrap();
+    // the 250 receive addresses used before and the gap are asked about in capped batches, then the change gap
+    assert_eq!(*source.0.borrow(), vec![100, 100, 71, 21]);
+    assert_eq!(cache.last_receive, None);
+}
+
+#[test]
+fn test_sync_statuses() {
+    use std::cell::RefCell;
+    use crate::server::source::History;
+    use crate::wallet::actions::single_sig_descriptor;
+    use bitcoin::{absolute::LockTime, TxIn, TxOut};
+    struct Statuses { funding: Transaction, fetched: RefCell<usize> }
+    impl ChainSource for Statuses {
+        fn tip(&self) -> Result<u32, Box<dyn Error>> { Ok(800_000) }
+        fn script_statuses(&self, scripts: &[ScriptBuf]) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+            Ok(scripts.iter().map(|script| Some(if *script == self.funding.output[0].script_pubkey { "used".into() } else { String::new() })).collect())
+        }
+        fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<History>, Box<dyn Error>> {
+            *self.fetched.borrow_mut() += scripts.len();
+            Ok(scripts.iter().map(|script| if *script == self.funding.output[0].script_pubkey { vec![(self.funding.txid(), 800_000)] } else { Vec::new() }).collect())
+        }
+        fn transactions(&self, _txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> { Ok(vec![self.funding.clone()]) }
+        fn fee_estimate(&self, _blocks: usize) -> Result<f64, Box<dyn Error>> { Ok(1.) }
+        fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> { Ok(tx.txid()) }
+    }
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let receive = cache.script(&wallet, true, 3).unwrap();
+    let funding = Transaction { version: 2, lock_time: LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 50_000, script_pubkey: receive }] };
+    let source = Statuses { funding, fetched: RefCell::new(0) };
+    sync(&wallet, 5, &source, &mut cache).unwrap();
+    assert_eq!(*source.fetched.borrow(), 12 + 6);
+    assert_eq!(cache.last_receive, Some(3));
+    assert_eq!(get_balance(&cache), 50_000);
+    // nothing changed, so the cached histories are used
+    sync(&wallet, 5, &source, &mut cache).unwrap();
+    assert_eq!(*source.fetched.borrow(), 12 + 6);
+    assert_eq!(cache.last_receive, Some(3));
+    assert_eq!(get_balance(&cache), 50_000);
+}
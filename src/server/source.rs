@@ -0,0 +1,506 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashMap, HashSet}, error::Error, io, path::Path, time::Duration};
+use bitcoin::{Amount, Script, ScriptBuf, Transaction, Txid};
+use bitcoincore_rpc::{jsonrpc::{self, simple_http}, json::{ImportDescriptors, Timestamp}, Auth, RpcApi};
+use electrum_client::{raw_client::RawClient, ConfigBuilder, ElectrumApi, Socks5Config};
+use esplora_client::BlockingClient;
+use serde_json::json;
+use crate::server::tls::pinned_stream;
+use crate::wallet::actions::WalletDescriptor;
+
+/// Esplora returns confirmed transactions for a script in pages of this size.
+const ESPLORA_PAGE: usize = 25;
+/// How many addresses of each keychain a Bitcoin Core wallet watches.
+const CORE_RANGE: usize = 1_000;
+/// Bitcoin Core lists wallet transactions in pages of this size. The count is a 32 bit integer to the node.
+const CORE_PAGE: usize = 1_000;
+/// Importing a descriptor rescans the chain, which can take a long time on mainnet.
+const CORE_IMPORT_TIMEOUT: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How to reach the configured servers.
+#[derive(Debug, Clone, Default)]
+pub struct ServerSettings {
+    /// "electrum", "esplora" or "bitcoind".
+    pub backend: String,
+    /// Bitcoin Core RPC credentials: user:password or the path to the cookie file.
+    pub auth: String,
+    /// A SOCKS5 proxy for Electrum and Esplora connections, as [host]:[port]. Empty connects directly.
+    pub proxy: String,
+    /// The trusted certificate fingerprints of Electrum servers, by URL.
+    pub certificates: BTreeMap<String, String>,
+}
+
+/// The transactions touching a script, with the height they confirmed at or 0 while unconfirmed.
+pub type History = Vec<(Txid, i32)>;
+
+/// Everything Coinline needs from the chain. Balances, UTXOs and history are computed locally
+/// from the histories and transactions a source returns.
+pub trait ChainSource {
+    /// Tells the backend which wallet is about to be synced, for backends that must watch its scripts.
+    /// The transactions already known need not be downloaded again.
+    fn watch(&self, _wallet: &WalletDescriptor, _known: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    /// The height of the best block.
+    fn tip(&self) -> Result<u32, Box<dyn Error>>;
+    /// A hash of each script's history that changes whenever the history does, empty for a script
+    /// without history, or None when the backend cannot tell and the history must be fetched.
+    fn script_statuses(&self, scripts: &[ScriptBuf]) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+        Ok(vec![None; scripts.len()])
+    }
+    fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<History>, Box<dyn Error>>;
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>>;
+    /// The fee an unconfirmed transaction pays, when the backend knows it without looking up the coins it spends.
+    fn mempool_fee(&self, _txid: &Txid) -> Result<Option<u64>, Box<dyn Error>> {
+        Ok(None)
+    }
+    /// The fee rate, in Satoshis per virtual byte, to confirm within the number of blocks.
+    fn fee_estimate(&self, blocks: usize) -> Result<f64, Box<dyn Error>>;
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>>;
+}
+
+pub struct ElectrumSource<E = electrum_client::Client> {
+    client: E,
+    /// A connection subscribes to a script once, and later statuses arrive as notifications.
+    subscribed: RefCell<HashSet<ScriptBuf>>,
+}
+
+impl ElectrumSource {
+    pub fn new(url: &str, proxy: &str) -> Result<Self, Box<dyn Error>> {
+        let socks5 = if proxy.is_empty() { None } else { Some(Socks5Config::new(proxy)) };
+        // onion services are authenticated by their address, and rarely have a certificate for it
+        let config = ConfigBuilder::new().socks5(socks5).validate_domain(!is_onion(url)).build();
+        Ok(ElectrumSource { client: electrum_client::Client::from_config(url, config)?, subscribed: RefCell::default() })
+    }
+}
+
+impl<E: ElectrumApi> ChainSource for ElectrumSource<E> {
+    fn tip(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.client.block_headers_subscribe()?.height as u32)
+    }
+
+    fn script_statuses(&self, scripts: &[ScriptBuf]) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+        let mut subscribed = self.subscribed.borrow_mut();
+        let new: Vec<&Script> = scripts.iter().filter(|script| !subscribed.contains(*script)).map(|script| script.as_script()).collect();
+        if new.is_empty() {
+            return Ok(vec![None; scripts.len()]);
+        }
+        let statuses: HashMap<&Script, String> = new.iter().copied()
+            .zip(self.client.batch_script_subscribe(new.iter())?)
+            .map(|(script, status)| (script, status.map(|status| status.iter().map(|byte| format!("{:02x}", byte)).collect()).unwrap_or_default()))
+            .collect();
+        subscribed.extend(new.iter().map(|script| (*script).to_owned()));
+        Ok(scripts.iter().map(|script| statuses.get(script.as_script()).cloned()).collect())
+    }
+
+    fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<History>, Box<dyn Error>> {
+        let histories = self.client.batch_script_get_history(scripts.iter().map(|script| script.as_script()))?;
+        Ok(histories.into_iter().map(|history| history.into_iter().map(|tx| (tx.tx_hash, tx.height.max(0))).collect()).collect())
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        Ok(self.client.batch_transaction_get(txids)?)
+    }
+
+    fn fee_estimate(&self, blocks: usize) -> Result<f64, Box<dyn Error>> {
+        // servers report BTC per kvB, often with more precision than a Satoshi, or -1 when they have no estimate
+        let per_kvb = self.client.estimate_fee(blocks)?.max(0.);
+        Ok(per_kvb * Amount::ONE_BTC.to_sat() as f64 / 1_000.0)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> {
+        Ok(self.client.transaction_broadcast(tx)?)
+    }
+}
+
+pub struct EsploraSource {
+    client: BlockingClient,
+}
+
+impl EsploraSource {
+    pub fn new(url: &str, proxy: &str) -> Result<Self, Box<dyn Error>> {
+        let mut builder = esplora_client::Builder::new(url.trim_end_matches('/'));
+        if !proxy.is_empty() {
+            // ureq resolves names through a SOCKS5 proxy, which onion addresses require
+            builder = builder.proxy(&format!("socks5://{proxy}"));
+        }
+        Ok(EsploraSource { client: builder.build_blocking()? })
+    }
+}
+
+impl ChainSource for EsploraSource {
+    fn tip(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.client.get_height()?)
+    }
+
+    fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<History>, Box<dyn Error>> {
+        let mut histories = Vec::new();
+        for script in scripts {
+            let mut history = Vec::new();
+            let mut last_seen = None;
+            loop {
+                let txs = self.client.scripthash_txs(script, last_seen)?;
+                let confirmed = txs.iter().filter(|tx| tx.status.confirmed).count();
+                last_seen = txs.last().map(|tx| tx.txid);
+                history.extend(txs.into_iter().map(|tx| (tx.txid, tx.status.block_height.unwrap_or(0) as i32)));
+                if confirmed < ESPLORA_PAGE {
+                    break;
+                }
+            }
+            histories.push(history);
+        }
+        Ok(histories)
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let mut txs = Vec::new();
+        for txid in txids {
+            txs.push(self.client.get_tx_no_opt(txid)?);
+        }
+        Ok(txs)
+    }
+
+    fn fee_estimate(&self, blocks: usize) -> Result<f64, Box<dyn Error>> {
+        let estimates = self.client.get_fee_estimates()?;
+        Ok(esplora_client::convert_fee_rate(blocks, estimates)? as f64)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> {
+        self.client.broadcast(tx)?;
+        Ok(tx.txid())
+    }
+}
+
+/// A Bitcoin Core node. The wallet descriptor is imported into a watch-only descriptor wallet,
+/// and the wallet's transactions are read back from it.
+pub struct CoreSource {
+    url: String,
+    auth: String,
+    node: bitcoincore_rpc::Client,
+    histories: RefCell<HashMap<ScriptBuf, History>>,
+    transactions: RefCell<HashMap<Txid, Transaction>>,
+}
+
+impl CoreSource {
+    /// Authentication is either user:password or the path to the node's cookie file.
+    pub fn new(url: &str, auth: &str) -> Result<Self, Box<dyn Error>> {
+        let url = url.trim_end_matches('/');
+        let node = rpc_client(url, auth, Duration::from_secs(15))?;
+        Ok(CoreSource { url: url.into(), auth: auth.into(), node, histories: RefCell::new(HashMap::new()), transactions: RefCell::new(HashMap::new()) })
+    }
+}
+
+impl ChainSource for CoreSource {
+    fn watch(&self, wallet: &WalletDescriptor, known: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        let descriptor = wallet.descriptor.to_string();
+        let checksum = descriptor.rsplit('#').next().unwrap_or_default();
+        let name = format!("coinline-{checksum}");
+        let wallet_url = format!("{}/wallet/{}", self.url, name);
+        if !self.node.list_wallets()?.contains(&name) && self.node.load_wallet(&name).is_err() {
+            // a blank descriptor wallet without private keys
+            self.node.call::<serde_json::Value>("createwallet", &[json!(name), json!(true), json!(true), json!(""), json!(false), json!(true)])?;
+            let requests: Vec<ImportDescriptors> = [(&wallet.receive, false), (&wallet.change, true)].iter().map(|(keychain, internal)| ImportDescriptors {
+                descriptor: keychain.to_string(),
+                timestamp: Timestamp::Time(0),
+                active: Some(true),
+                range: Some((0, CORE_RANGE - 1)),
+                next_index: None,
+                internal: Some(*internal),
+                label: None,
+            }).collect();
+            let importer = rpc_client(&wallet_url, &self.auth, CORE_IMPORT_TIMEOUT)?;
+            let results: Vec<serde_json::Value> = importer.call("importdescriptors", &[serde_json::to_value(requests)?])?;
+            if results.iter().any(|result| result["success"] != json!(true)) {
+                return Err(Box::new(io::Error::other(format!("Bitcoin Core could not import the descriptor: {:?}", results))));
+            }
+        }
+        let client = rpc_client(&wallet_url, &self.auth, Duration::from_secs(60))?;
+        let mut heights = HashMap::new();
+        let mut skip = 0;
+        loop {
+            let page = client.list_transactions(None, Some(CORE_PAGE), Some(skip), Some(true))?;
+            for entry in &page {
+                let height = if entry.info.confirmations > 0 { entry.info.blockheight.unwrap_or(0) as i32 } else { 0 };
+                heights.insert(entry.info.txid, height);
+            }
+            if page.len() < CORE_PAGE {
+                break;
+            }
+            skip += CORE_PAGE;
+        }
+        let mut transactions: HashMap<Txid, Transaction> = known.iter().filter(|tx| heights.contains_key(&tx.txid())).map(|tx| (tx.txid(), tx.clone())).collect();
+        for txid in heights.keys() {
+            if !transactions.contains_key(txid) {
+                transactions.insert(*txid, client.get_transaction(txid, Some(true))?.transaction()?);
+            }
+        }
+        // a script's history holds the transactions paying to it and the ones spending from it
+        let mut histories: HashMap<ScriptBuf, History> = HashMap::new();
+        for (txid, tx) in &transactions {
+            let spent = tx.input.iter().filter_map(|input| transactions.get(&input.previous_output.txid)?.output.get(input.previous_output.vout as usize));
+            for output in tx.output.iter().chain(spent) {
+                let history = histories.entry(output.script_pubkey.clone()).or_default();
+                if !history.iter().any(|(id, _)| id == txid) {
+                    history.push((*txid, heights[txid]));
+                }
+            }
+        }
+        *self.histories.borrow_mut() = histories;
+        *self.transactions.borrow_mut() = transactions;
+        Ok(())
+    }
+
+    fn tip(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.node.get_block_count()? as u32)
+    }
+
+    fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<History>, Box<dyn Error>> {
+        let histories = self.histories.borrow();
+        Ok(scripts.iter().map(|script| histories.get(script).cloned().unwrap_or_default()).collect())
+    }
+
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let transactions = self.transactions.borrow();
+        let mut txs = Vec::new();
+        for txid in txids {
+            match transactions.get(txid) {
+                Some(tx) => txs.push(tx.clone()),
+                None => txs.push(self.node.get_raw_transaction(txid, None)?),
+            }
+        }
+        Ok(txs)
+    }
+
+    fn mempool_fee(&self, txid: &Txid) -> Result<Option<u64>, Box<dyn Error>> {
+        // the coins a transaction spends cannot be looked up without -txindex, but the mempool knows its fee
+        match self.node.call::<serde_json::Value>("getmempoolentry", &[json!(txid)]) {
+            Ok(entry) => {
+                let base = entry["fees"]["base"].as_f64().ok_or(io::Error::other("The mempool entry has no fee"))?;
+                Ok(Some((base * Amount::ONE_BTC.to_sat() as f64).round() as u64))
+            },
+            // the transaction is not in the mempool
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(_))) => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    fn fee_estimate(&self, blocks: usize) -> Result<f64, Box<dyn Error>> {
+        let estimate = self.node.estimate_smart_fee(blocks as u16, None)?;
+        // nodes without enough data, such as on regtest, fall back to the minimum relay fee
+        Ok(estimate.fee_rate.map_or(1.0, |rate| rate.to_sat() as f64 / 1_000.0))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> {
+        Ok(self.node.send_raw_transaction(tx)?)
+    }
+}
+
+fn rpc_client(url: &str, auth: &str, timeout: Duration) -> Result<bitcoincore_rpc::Client, Box<dyn Error>> {
+    let auth = if auth.is_empty() {
+        Auth::None
+    } else if Path::new(auth).exists() {
+        Auth::CookieFile(auth.into())
+    } else if let Some((user, pass)) = auth.split_once(':') {
+        Auth::UserPass(user.into(), pass.into())
+    } else {
+        Auth::CookieFile(auth.into())
+    };
+    let mut builder = simple_http::Builder::new().url(url)?.timeout(timeout);
+    if let (Some(user), pass) = auth.get_user_pass()? {
+        builder = builder.auth(user, pass);
+    }
+    Ok(bitcoincore_rpc::Client::from_jsonrpc(jsonrpc::Client::with_transport(builder.build())))
+}
+
+/// Whether the server is a Tor onion service, which can only be reached through a proxy.
+pub fn is_onion(url: &str) -> bool {
+    let host = url.split("://").last().unwrap_or(url);
+    let host = host.split(['/', ':']).next().unwrap_or(host);
+    host.ends_with(".onion")
+}
+
+/// Connects to the server with the configured backend. Electrum and Esplora connections go through
+/// the SOCKS5 proxy when one is set, and Electrum servers with a trusted certificate must present it.
+pub fn connect(url: &str, settings: &ServerSettings) -> Result<Box<dyn ChainSource>, Box<dyn Error>> {
+    let proxy = settings.proxy.as_str();
+    if is_onion(url) && proxy.is_empty() {
+        return Err(Box::new(io::Error::other("Onion servers can only be reached through a proxy. Set one with the 'proxy' command")));
+    }
+    match settings.backend.as_str() {
+        "electrum" => match settings.certificates.get(url) {
+            Some(fingerprint) => Ok(Box::new(ElectrumSource { client: RawClient::from(pinned_stream(url, fingerprint, proxy)?), subscribed: RefCell::default() })),
+            None => Ok(Box::new(ElectrumSource::new(url, proxy)?)),
+        },
+        "esplora" => Ok(Box::new(EsploraSource::new(url, proxy)?)),
+        "bitcoind" if !proxy.is_empty() => Err(Box::new(io::Error::other("Bitcoin Core connections cannot use the proxy. Connect to a node you control or remove the proxy"))),
+        "bitcoind" => Ok(Box::new(CoreSource::new(url, &settings.auth)?)),
+        backend => Err(Box::new(io::Error::other(format!("Unknown backend {backend}. Use electrum, esplora or bitcoind")))),
+    }
+}
+
+#[test]
+fn test_esplora_source() {
+    use std::{io::{Read, Write}, net::TcpListener, thread};
+    use bitcoin::{absolute::LockTime, consensus::encode::serialize, hashes::{sha256, Hash}, TxOut};
+    let tx = Transaction { version: 2, lock_time: LockTime::ZERO, input: vec![], output: vec![TxOut { value: 50_000, script_pubkey: ScriptBuf::new() }] };
+    let script = ScriptBuf::from_bytes(vec![0x00, 0x14, 0x01]);
+    let script_hash = sha256::Hash::hash(script.as_bytes());
+    let history = format!(r#"[{{"txid":"{}","version":2,"locktime":0,"vin":[],"vout":[],"status":{{"confirmed":true,"block_height":800000}},"fee":0}}]"#, tx.txid());
+    let raw = serialize(&tx);
+    let txid = tx.txid();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 1024];
+            let read = stream.read(&mut request).unwrap();
+            let request = String::from_utf8_lossy(&request[..read]);
+            let path = request.split_whitespace().nth(1).unwrap_or_default().to_string();
+            let body = if path == "/blocks/tip/height" {
+                b"800010".to_vec()
+            } else if path == "/fee-estimates" {
+                br#"{"1": 25.5, "6": 12.0, "144": 1.0}"#.to_vec()
+            } else if path == format!("/scripthash/{:x}/txs", script_hash) {
+                history.as_bytes().to_vec()
+            } else if path == format!("/tx/{}/raw", txid) {
+                raw.clone()
+            } else {
+                b"[]".to_vec()
+            };
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    let settings = ServerSettings { backend: "esplora".into(), ..Default::default() };
+    let source = connect(&url, &settings).unwrap();
+    assert_eq!(source.tip().unwrap(), 800_010);
+    assert_eq!(source.fee_estimate(10).unwrap(), 12.0);
+    let histories = source.script_histories(&[script, ScriptBuf::new()]).unwrap();
+    assert_eq!(histories, vec![vec![(tx.txid(), 800_000)], vec![]]);
+    assert_eq!(source.transactions(&[tx.txid()]).unwrap(), vec![tx]);
+}
+
+#[test]
+fn test_core_source() {
+    use std::{io::{BufRead, BufReader, Read, Write}, net::TcpListener, sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread};
+    use bitcoin::{absolute::LockTime, consensus::encode::serialize, Network, TxIn, TxOut};
+    use crate::wallet::actions::single_sig_descriptor;
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let name = format!("coinline-{}", wallet.descriptor.to_string().rsplit('#').next().unwrap());
+    let script = ScriptBuf::from_bytes(vec![0x00, 0x14, 0x01]);
+    // more wallet transactions than fit in one page
+    let txs: Vec<Transaction> = (0..CORE_PAGE as u64 + 5).map(|i| Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn::default()],
+        output: vec![TxOut { value: 1_000 + i, script_pubkey: script.clone() }],
+    }).collect();
+    let entries: Vec<serde_json::Value> = txs.iter().enumerate().map(|(i, tx)| {
+        let confirmations = if i == 0 { 0 } else { 10 };
+        json!({"txid": tx.txid(), "confirmations": confirmations, "blockheight": 800_000, "category": "receive", "amount": 0.00001, "vout": 0, "time": 0, "timereceived": 0, "bip125-replaceable": "no", "walletconflicts": []})
+    }).collect();
+    let downloads = Arc::new(AtomicUsize::new(0));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (served, counter) = (Arc::new(txs.clone()), downloads.clone());
+    let (name, entries) = (Arc::new(name), Arc::new(entries));
+    thread::spawn(move || {
+        // the node and the wallet are separate connections, kept alive between requests
+        for stream in listener.incoming() {
+            let (served, counter, name, entries) = (served.clone(), counter.clone(), name.clone(), entries.clone());
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.unwrap());
+                'requests: loop {
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            break 'requests;
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length: ") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    let params = &request["params"];
+                    let (result, error) = match request["method"].as_str().unwrap() {
+                        "listwallets" => (json!([*name]), json!(null)),
+                        "getblockcount" => (json!(800_010), json!(null)),
+                        // the node reads the count as a 32 bit integer
+                        "listtransactions" => match params[1].as_u64().filter(|count| *count <= i32::MAX as u64) {
+                            Some(count) => {
+                                let skip = params[2].as_u64().unwrap_or(0) as usize;
+                                (json!(entries.iter().skip(skip).take(count as usize).collect::<Vec<_>>()), json!(null))
+                            },
+                            None => (json!(null), json!({"code": -1, "message": "JSON integer out of range"})),
+                        },
+                        "gettransaction" => {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            let tx = served.iter().find(|tx| json!(tx.txid()) == params[0]).unwrap();
+                            let hex: String = serialize(tx).iter().map(|byte| format!("{:02x}", byte)).collect();
+                            (json!({"txid": tx.txid(), "confirmations": 10, "time": 0, "timereceived": 0, "bip125-replaceable": "no", "walletconflicts": [], "amount": 0.00001, "details": [], "hex": hex}), json!(null))
+                        },
+                        "getmempoolentry" if params[0] == json!(served[0].txid()) => (json!({"vsize": 110, "fees": {"base": 0.00001234, "modified": 0.00001234}}), json!(null)),
+                        "getmempoolentry" => (json!(null), json!({"code": -5, "message": "Transaction not in mempool"})),
+                        method => (json!(null), json!({"code": -32601, "message": format!("Method not found: {method}")})),
+                    };
+                    let body = json!({"result": result, "error": error, "id": request["id"]}).to_string();
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                    reader.get_mut().write_all(response.as_bytes()).unwrap();
+                }
+            });
+        }
+    });
+    let source = CoreSource::new(&url, "user:password").unwrap();
+    assert_eq!(source.tip().unwrap(), 800_010);
+    // the first transaction is already in the local cache, so only the rest are downloaded
+    source.watch(&wallet, &txs[..1]).unwrap();
+    assert_eq!(downloads.load(Ordering::SeqCst), txs.len() - 1);
+    let histories = source.script_histories(&[script]).unwrap();
+    assert_eq!(histories[0].len(), txs.len());
+    assert!(histories[0].contains(&(txs[0].txid(), 0)));
+    assert!(histories[0].contains(&(txs[1].txid(), 800_000)));
+    assert_eq!(source.transactions(&[txs[0].txid()]).unwrap(), vec![txs[0].clone()]);
+    assert_eq!(source.mempool_fee(&txs[0].txid()).unwrap(), Some(1_234));
+    assert_eq!(source.mempool_fee(&txs[1].txid()).unwrap(), None);
+}
+
+#[test]
+fn test_electrum_fee_estimate() {
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener, thread};
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("tcp://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        for line in BufReader::new(stream).lines() {
+            let request: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+            // Bitcoin Core estimates are not rounded to a Satoshi, and -1 means there is no estimate
+            let result = if request["params"][0] == 1 { json!(0.00012345678) } else { json!(-1) };
+            let response = json!({ "jsonrpc": "2.0", "id": request["id"], "result": result });
+            writer.write_all(format!("{response}\n").as_bytes()).unwrap();
+        }
+    });
+    let source = ElectrumSource::new(&url, "").unwrap();
+    assert!((source.fee_estimate(1).unwrap() - 12.345678).abs() < 1e-9);
+    assert_eq!(source.fee_estimate(2).unwrap(), 0.);
+}
+
+#[test]
+fn test_onion_urls() {
+    assert!(is_onion("tcp://explorerzydxu5ecjrkwceayqybizmpjjznk5izmitf2modhcusuqlid.onion:110"));
+    assert!(is_onion("http://mempoolhqx4isw62xs7abwphsq7ldayuidyx2v2oethdhhj6mlo2r6ad.onion/api"));
+    assert!(!is_onion("ssl://electrum.blockstream.info:50002"));
+    let settings = ServerSettings { backend: "electrum".into(), ..Default::default() };
+    assert!(connect("tcp://explorerzydxu5ecjrkwceayqybizmpjjznk5izmitf2modhcusuqlid.onion:110", &settings).is_err());
+    // building an Esplora client does not connect, so only the proxy address is checked
+    assert!(EsploraSource::new("http://mempoolhqx4isw62xs7abwphsq7ldayuidyx2v2oethdhhj6mlo2r6ad.onion/api", "127.0.0.1:9050").is_ok());
+}
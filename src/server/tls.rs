@@ -0,0 +1,101 @@
+use std::{error::Error, io, net::TcpStream, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+use bitcoin::hashes::{sha256, Hash};
+use electrum_client::socks::Socks5Stream;
+use rustls::{client::{ServerCertVerified, ServerCertVerifier}, Certificate, ClientConfig, ClientConnection, ServerName, StreamOwned};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Accepts the server's certificate only when its SHA256 fingerprint is the trusted one. With nothing
+/// trusted yet, any certificate is accepted so its fingerprint can be recorded.
+struct FingerprintVerifier {
+    trusted: Option<String>,
+    seen: Mutex<Option<String>>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = sha256::Hash::hash(&end_entity.0).to_string();
+        *self.seen.lock().unwrap() = Some(fingerprint.clone());
+        match &self.trusted {
+            Some(trusted) if *trusted != fingerprint => Err(rustls::Error::General(format!("The certificate fingerprint {fingerprint} does not match the trusted {trusted}. If the server changed its certificate, trust it again with the 'client' command and --trust"))),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+fn handshake(url: &str, proxy: &str, verifier: Arc<FingerprintVerifier>) -> Result<StreamOwned<ClientConnection, TcpStream>, Box<dyn Error>> {
+    let address = url.strip_prefix("ssl://").ok_or(io::Error::other("Only ssl:// Electrum servers have a certificate"))?;
+    let (host, port) = address.rsplit_once(':').ok_or(io::Error::other("The server URL needs a port"))?;
+    let mut tcp = if proxy.is_empty() {
+        TcpStream::connect((host, port.parse::<u16>()?))?
+    } else {
+        Socks5Stream::connect(proxy, address, Some(TIMEOUT))?.into_inner()
+    };
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+    let config = ClientConfig::builder().with_safe_defaults().with_custom_certificate_verifier(verifier).with_no_client_auth();
+    let mut connection = ClientConnection::new(Arc::new(config), ServerName::try_from(host)?)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut tcp)?;
+    }
+    Ok(StreamOwned::new(connection, tcp))
+}
+
+/// The SHA256 fingerprint of the certificate an Electrum server presents, for trust on first use.
+pub fn certificate_fingerprint(url: &str, proxy: &str) -> Result<String, Box<dyn Error>> {
+    let verifier = Arc::new(FingerprintVerifier { trusted: None, seen: Mutex::new(None) });
+    handshake(url, proxy, verifier.clone())?;
+    let seen = verifier.seen.lock().unwrap().clone();
+    seen.ok_or(Box::new(io::Error::other("The server did not present a certificate")))
+}
+
+/// A TLS connection to an Electrum server that fails unless the server presents the trusted certificate.
+pub fn pinned_stream(url: &str, fingerprint: &str, proxy: &str) -> Result<StreamOwned<ClientConnection, TcpStream>, Box<dyn Error>> {
+    handshake(url, proxy, Arc::new(FingerprintVerifier { trusted: Some(fingerprint.into()), seen: Mutex::new(None) }))
+}
+
+#[test]
+fn test_certificate_pinning() {
+    use std::{io::{Read, Write}, net::TcpListener, thread};
+    use rustls::{PrivateKey, ServerConfig, ServerConnection};
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let der = certificate.serialize_der().unwrap();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![Certificate(der.clone())], PrivateKey(certificate.serialize_private_key_der()))
+        .unwrap();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ssl://localhost:{}", listener.local_addr().unwrap().port());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut connection = ServerConnection::new(config.clone()).unwrap();
+            while connection.is_handshaking() {
+                if connection.complete_io(&mut stream).is_err() {
+                    break;
+                }
+            }
+            let mut tls = StreamOwned::new(connection, stream);
+            let _ = tls.write_all(b"pinned\n");
+            let _ = tls.flush();
+        }
+    });
+    let fingerprint = certificate_fingerprint(&url, "").unwrap();
+    assert_eq!(fingerprint, sha256::Hash::hash(&der).to_string());
+    let mut stream = pinned_stream(&url, &fingerprint, "").unwrap();
+    let mut greeting = [0; 7];
+    stream.read_exact(&mut greeting).unwrap();
+    assert_eq!(&greeting, b"pinned\n");
+    let other = sha256::Hash::hash(b"another certificate").to_string();
+    assert!(pinned_stream(&url, &other, "").is_err());
+}
@@ -1 +1,2 @@
+#[allow(clippy::module_inception)]
 pub mod system;
\ No newline at end of file
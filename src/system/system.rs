@@ -1,5 +1,6 @@
 #![allow(unused_variables, unused_imports, unused_assignments)]
 use bitcoin::bip32::{ExtendedPubKey, Fingerprint};
+use bitcoin::{Address, Network};
 use regex::Regex;
 use serde::Serialize;
 use xyzpub::{convert_version, Version};
@@ -7,10 +8,11 @@ use std::{error::Error, fs::{File, self}, io::{Read, self, ErrorKind, Write}, st
 use serde_json::Value;
 use walkdir::WalkDir;
 use dirs;
-use crate::wallet::actions::compute_address;
+use crate::wallet::actions::{compute_address, single_sig_descriptor, Recipient, WalletDescriptor};
 
 
-pub fn import_coldcard_from_json(path: PathBuf) -> Result<(Fingerprint, ExtendedPubKey), Box<dyn Error>> {
+/// Reads the generic JSON export of a Coldcard and returns the Native Segwit descriptor.
+pub fn import_coldcard_from_json(path: PathBuf, network: Network) -> Result<String, Box<dyn Error>> {
     let mut file = File::open(path)?;
     let mut content = String::new();
     file.read_to_string(&mut content)?;
@@ -20,31 +22,98 @@ pub fn import_coldcard_from_json(path: PathBuf) -> Result<(Fingerprint, Extended
     let master_first_addr = &json_data["bip84"]["first"];
     let cleaned_first_addr: String = master_first_addr.to_string().chars().filter(|c| c.is_alphanumeric()).collect();
     let str_xpub: String = master_xpub.chars().filter(|c| c.is_alphanumeric()).collect();
-    let test_first_addr = compute_address(str_xpub.as_str(), true, 0)?.to_string();
+    let descriptor = single_sig_descriptor(&master_fp.replace('"', ""), &str_xpub, network)?;
+    let wallet = WalletDescriptor::new(&descriptor, network)?;
+    let test_first_addr = compute_address(&wallet, true, 0)?.to_string();
     assert_eq!(cleaned_first_addr, test_first_addr);
-    let fingerprint = Fingerprint::from_str(&master_fp.to_string().replace("\"", ""))?;
-    let root = ExtendedPubKey::from_str(&master_xpub.to_string().replace("\"", ""))?;
-    Ok((fingerprint, root))
+    Ok(descriptor)
 }
 
-pub fn import_keystone_from_txt(path: PathBuf) -> Result<(Fingerprint, ExtendedPubKey), Box<dyn Error>> {
+/// Reads the Keystone wallet export and returns the Native Segwit descriptor.
+pub fn import_keystone_from_txt(path: PathBuf, network: Network) -> Result<String, Box<dyn Error>> {
     let mut file = File::open(path)?;
     let mut content = String::new();
     file.read_to_string(&mut content)?;
     let start_index = content.find('[').unwrap() + 1;
     let end_index = content.find('/').unwrap();
     let fp: &str = &content[start_index..end_index];
-    let fingerprint = Fingerprint::from_str(fp)?;
 
-    let re: Regex = Regex::new(r"zpub[^/]+")?;
+    // Keystone exports a zpub on mainnet and a vpub on the test networks
+    let pattern = if network == Network::Bitcoin { r"zpub[^/]+" } else { r"vpub[^/]+" };
+    let re: Regex = Regex::new(pattern)?;
     if let Some(capture) = re.find(&content) {
         let extracted_part = capture.as_str();
-        let result = convert_version(extracted_part, &Version::Xpub).expect("error converting xpub");
-        let root = ExtendedPubKey::from_str(&result)?;
-        return Ok((fingerprint, root));
+        single_sig_descriptor(fp, extracted_part, network)
     } else {
-        let err = io::Error::new(ErrorKind::Other, "Could not find zpub.");
-        return Err(Box::new(err));
+        let err = io::Error::other("Could not find zpub or vpub.");
+        Err(Box::new(err))
     }
 }
 
+
+/// Reads a list of payments, either a CSV of address,amount lines, optionally with a header, or a
+/// JSON list of objects with an address and an amount in Satoshis. Every address must belong to the
+/// network and appear only once.
+pub fn import_recipients(path: PathBuf, network: Network) -> Result<Vec<Recipient>, Box<dyn Error>> {
+    let content = fs::read_to_string(&path)?;
+    let mut recipients = Vec::new();
+    if content.trim_start().starts_with('[') {
+        let json_data: Value = serde_json::from_str(&content)?;
+        for entry in json_data.as_array().into_iter().flatten() {
+            let address = entry["address"].as_str().ok_or(io::Error::other("Each recipient needs an address"))?;
+            let amount = entry["amount"].as_u64().ok_or(io::Error::other(format!("{address} needs an amount in Satoshis")))?;
+            recipients.push(Recipient { address: address.into(), amount: Some(amount) });
+        }
+        return check_recipients(recipients, network);
+    }
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (address, amount) = line.split_once(',').ok_or(io::Error::other(format!("Line {} is not address,amount", i + 1)))?;
+        match amount.trim().parse::<u64>() {
+            Ok(amount) => recipients.push(Recipient { address: address.trim().into(), amount: Some(amount) }),
+            Err(_) if i == 0 => continue,
+            Err(_) => return Err(Box::new(io::Error::other(format!("Line {} has an invalid amount", i + 1)))),
+        }
+    }
+    check_recipients(recipients, network)
+}
+
+fn check_recipients(recipients: Vec<Recipient>, network: Network) -> Result<Vec<Recipient>, Box<dyn Error>> {
+    for (i, recipient) in recipients.iter().enumerate() {
+        Address::from_str(&recipient.address)?.require_network(network)?;
+        if recipients[..i].iter().any(|earlier| earlier.address == recipient.address) {
+            return Err(Box::new(io::Error::other(format!("{} is paid more than once", recipient.address))));
+        }
+    }
+    Ok(recipients)
+}
+
+#[test]
+fn test_import_recipients() {
+    let dir = std::env::temp_dir().join(format!("coinline-batch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let import = |name: &str, content: &str| {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        import_recipients(path, Network::Bitcoin)
+    };
+    let first = "bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a";
+    let second = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+    let csv = import("payments.csv", &format!("address,amount\n{first},10000\n\n {second} , 20000\n")).unwrap();
+    assert_eq!(csv.len(), 2);
+    assert_eq!((csv[1].address.as_str(), csv[1].amount), (second, Some(20_000)));
+    let json = import("payments.json", &format!(r#"[{{"address":"{first}","amount":10000}},{{"address":"{second}","amount":20000}}]"#)).unwrap();
+    assert_eq!(json.len(), 2);
+    assert_eq!((json[0].address.as_str(), json[0].amount), (first, Some(10_000)));
+    // only the first line may be a header
+    assert!(import("malformed.csv", &format!("{first},10000\n{second},twenty")).is_err());
+    assert!(import("missing.csv", &format!("{first},10000\n{second}")).is_err());
+    assert!(import("malformed.json", &format!(r#"[{{"address":"{first}","amount":"10000"}}]"#)).is_err());
+    assert!(import("duplicate.csv", &format!("{first},10000\n{first},20000")).is_err());
+    assert!(import("testnet.csv", "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx,10000").is_err());
+    assert!(import("invalid.csv", "not an address,10000").is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
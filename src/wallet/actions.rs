@@ -12,162 +12,363 @@ use bitcoin::bip32::{ChildNumber, ExtendedPubKey, DerivationPath, Fingerprint, I
 use bitcoin::psbt::{Psbt, Input, PsbtSighashType, Output, self};
 use bitcoin::secp256k1::ffi::types::AlignedType;
 use bitcoin::secp256k1::Secp256k1;
-use bitcoin::{PublicKey, ScriptBuf, Txid, TxOut, Sequence, TxIn, Witness, Transaction, absolute, OutPoint};
+use bitcoin::{PublicKey, Script, ScriptBuf, VarInt, Txid, TxOut, Sequence, TxIn, Witness, Transaction, absolute, OutPoint, Network};
 use colored::Colorize;
-use miniscript::descriptor::Tr;
+use miniscript::descriptor::{DescriptorPublicKey, DefiniteDescriptorKey, DescriptorType, WshInner};
 use miniscript::psbt::PsbtExt;
+use miniscript::{Descriptor, ForEachKey};
+use rand::Rng;
 use rayon::prelude::*;
 use xyzpub::{convert_version, Version};
 
+use crate::server::{cache::WalletCache, source::ChainSource};
 use crate::server::server::{get_all_utxo, get_fresh_change, get_fee_estimate, broadcast};
+use crate::wallet::selection::{SelectionParams, ALGORITHMS, largest_first, smallest_first, branch_and_bound, single_random_draw, least_waste};
 
 // use crate::server::server::{get_fee_estimate, get_all_utxo, get_fresh_change};
 
-const VERSION_BYTE_FEE: f64 = 4.;
-const LOCKTIME_BYTE_FEE: f64 = 4.;
-const INPUT_COUNTER_BYTE_FEE: f64 = 9.;
-const OUTPUT_COUNTER_BYTE_FEE: f64 = 9.;
-const INPUT_BYTE_FEE: f64 = 147.;
+const VERSION_WEIGHT: u64 = 16;
+const LOCKTIME_WEIGHT: u64 = 16;
+/// The segwit marker and flag are witness data, one weight unit each.
+const SEGWIT_HEADER_WEIGHT: u64 = 2;
+/// The outpoint, sequence and empty script sig, plus the witness item count, before the witness is added.
+const TXIN_BASE_WEIGHT: u64 = 4 * (32 + 4 + 1 + 4) + 1;
+/// The fee estimate for this many blocks stands in for the long-term fee rate when weighing coin selections.
+const LONG_TERM_BLOCKS: usize = 1008;
+/// BIP125 replacements pay for their own size at this rate on top of the fee they replace.
+const INCREMENTAL_RELAY_FEE_RATE: f64 = 1.;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SelectionUTXO {
     pub id: Txid,
     pub index: usize,
-    pub value: f64,
+    pub value: u64,
     pub script: ScriptBuf,
     pub info: AddressInfo,
 }
+impl SelectionUTXO {
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint { txid: self.id, vout: self.index as u32 }
+    }
+}
+
+/// Which coins a transaction may spend. When coins are included, all of them are spent and nothing else.
+/// Excluded coins are never spent.
+#[derive(Debug, Clone, Default)]
+pub struct CoinControl {
+    pub include: Vec<OutPoint>,
+    pub exclude: Vec<OutPoint>,
+}
+
+/// How a transaction is locked. Without options it is locked to the anti-fee-sniping height and signals replace-by-fee.
+#[derive(Debug, Clone, Default)]
+pub struct TxOptions {
+    /// A block height or time before which the transaction cannot be mined, instead of the anti-fee-sniping height.
+    pub lock_time: Option<absolute::LockTime>,
+    /// A relative lock on every input, instead of only signalling replace-by-fee.
+    pub sequence: Option<Sequence>,
+}
+
+/// A payment to one address. A recipient without an amount receives whatever is left after the others and the fee.
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub address: String,
+    pub amount: Option<u64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct AddressInfo {
     pub address: bitcoin::Address,
-    pub path_to: DerivationPath,
-    pub public_key: bitcoin::secp256k1::PublicKey,
+    pub external: bool,
+    pub index: u32,
 }
 
-pub fn compute_address(xpub: &str, external: bool, ind: u32) -> Result<Address, Box<dyn Error>>{
-    // initialize the eliptic curve
-    let network = bitcoin::Network::Bitcoin;
-    let mut buf: Vec<AlignedType> = Vec::new();
-    buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
-    let secp = Secp256k1::preallocated_new(buf.as_mut_slice())?;
-    // standardize zpub to xpub
-    let result = convert_version(xpub, &Version::Xpub).expect("error converting xpub");
-    let root = ExtendedPubKey::from_str(&result)?;
-    let is_external = if external { 0 } else { 1 };
-    let internal = ChildNumber::from_normal_idx(is_external)?;
-    let index = ChildNumber::from_normal_idx(ind)?;
-    let public_key = root.derive_pub(&secp, &[internal, index])?.public_key;
-    // segwit only
-    let address = Address::p2wpkh(&PublicKey::new(public_key), network)?;
+/// A wallet defined by a multipath output descriptor, e.g. `wpkh([fp/84h/0h/0h]xpub/<0;1>/*)`,
+/// `tr([fp/86h/0h/0h]xpub/<0;1>/*)` or `wsh(sortedmulti(2,[fp/48h/0h/0h/2h]xpub/<0;1>/*,...))`,
+/// split into its receiving and change keychains.
+#[derive(Debug, Clone)]
+pub struct WalletDescriptor {
+    pub descriptor: Descriptor<DescriptorPublicKey>,
+    pub receive: Descriptor<DescriptorPublicKey>,
+    pub change: Descriptor<DescriptorPublicKey>,
+    pub network: Network,
+}
+
+impl WalletDescriptor {
+    pub fn new(descriptor: &str, network: Network) -> Result<Self, Box<dyn Error>> {
+        // accept the wallet policy shorthand used by Ledger
+        let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&descriptor.replace("/**", "/<0;1>/*"))?;
+        if !matches!(descriptor.desc_type(), DescriptorType::Wpkh | DescriptorType::Tr | DescriptorType::WshSortedMulti) {
+            return Err(io::Error::other("Only wpkh, tr and wsh(sortedmulti) descriptors are supported").into());
+        }
+        if let Descriptor::Tr(tr) = &descriptor {
+            if tr.taptree().is_some() {
+                return Err(io::Error::other("Only key path tr descriptors are supported").into());
+            }
+        }
+        if !descriptor.has_wildcard() {
+            return Err(io::Error::other("The descriptor must end in a wildcard, e.g. /<0;1>/*").into());
+        }
+        let key_network = if network == Network::Bitcoin { Network::Bitcoin } else { Network::Testnet };
+        let mut wrong_network = false;
+        descriptor.for_each_key(|key| {
+            match key {
+                DescriptorPublicKey::XPub(xkey) => wrong_network |= xkey.xkey.network != key_network,
+                DescriptorPublicKey::MultiXPub(xkey) => wrong_network |= xkey.xkey.network != key_network,
+                DescriptorPublicKey::Single(_) => (),
+            }
+            true
+        });
+        if wrong_network {
+            return Err(io::Error::other(format!("The descriptor keys do not belong to {}", network)).into());
+        }
+        let keychains = descriptor.clone().into_single_descriptors()?;
+        if keychains.len() != 2 {
+            return Err(io::Error::other("The descriptor must have a receive and change path, e.g. /<0;1>/*").into());
+        }
+        Ok(WalletDescriptor { descriptor, receive: keychains[0].clone(), change: keychains[1].clone(), network })
+    }
+
+    /// The descriptor for a single address.
+    pub fn derive(&self, external: bool, ind: u32) -> Result<Descriptor<DefiniteDescriptorKey>, Box<dyn Error>> {
+        let keychain = if external { &self.receive } else { &self.change };
+        Ok(keychain.at_derivation_index(ind)?)
+    }
+
+    /// The full path to an address for display, e.g. m/84'/0'/0'/0/5.
+    pub fn display_path(&self, external: bool, ind: u32) -> String {
+        let is_external = if external { 0 } else { 1 };
+        let keychain = if external { &self.receive } else { &self.change };
+        let mut origin = None;
+        keychain.for_each_key(|key| {
+            origin = key.full_derivation_path();
+            false
+        });
+        match origin {
+            Some(path) => format!("{}/{}", path, ind),
+            None => format!("{}/{}", is_external, ind),
+        }
+    }
+
+    /// The signatures required and the number of cosigners, (1, 1) for a single signer.
+    pub fn threshold(&self) -> (usize, usize) {
+        if let Descriptor::Wsh(wsh) = &self.descriptor {
+            if let WshInner::SortedMulti(multi) = wsh.as_inner() {
+                return (multi.k, multi.pks.len());
+            }
+        }
+        (1, 1)
+    }
+
+    pub fn is_multisig(&self) -> bool {
+        self.threshold().1 > 1
+    }
+
+    pub fn is_taproot(&self) -> bool {
+        self.descriptor.desc_type() == DescriptorType::Tr
+    }
+
+    /// The weight of spending one of the wallet's coins, with the largest witness the descriptor can be
+    /// satisfied with: 73 byte ECDSA signatures, or a 66 byte Schnorr signature for a taproot key path.
+    pub fn input_weight(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(TXIN_BASE_WEIGHT + self.receive.max_weight_to_satisfy()? as u64)
+    }
+
+    /// The weight of a change output.
+    pub fn change_weight(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(output_weight(&self.derive(false, 0)?.script_pubkey()))
+    }
+
+    /// The wallet policy registered with and used for signing on a Ledger.
+    pub fn ledger_policy(&self) -> String {
+        let descriptor = self.descriptor.to_string();
+        let without_checksum = descriptor.split('#').next().unwrap_or_default();
+        without_checksum.replace("/<0;1>/*", "/**")
+    }
+
+    /// Every extended key in the descriptor with its origin, for the PSBT global xpub map.
+    pub fn xpubs(&self) -> BTreeMap<ExtendedPubKey, (Fingerprint, DerivationPath)> {
+        let mut map = BTreeMap::new();
+        self.receive.for_each_key(|key| {
+            if let DescriptorPublicKey::XPub(xkey) = key {
+                let origin = xkey.origin.clone().unwrap_or((xkey.xkey.fingerprint(), DerivationPath::master()));
+                map.insert(xkey.xkey, origin);
+            }
+            true
+        });
+        map
+    }
+}
+
+/// The weight of an output paying to the script: the value, the script length and the script, none of it discounted.
+pub fn output_weight(script: &Script) -> u64 {
+    4 * (8 + VarInt(script.len() as u64).len() + script.len()) as u64
+}
+
+/// The weight of a segwit transaction paying the recipients before any inputs or change are added,
+/// counting one byte for the number of inputs.
+pub fn base_weight(recipients: &[ScriptBuf]) -> u64 {
+    let output_count = VarInt(recipients.len() as u64 + 1).len() as u64;
+    let outputs: u64 = recipients.iter().map(|script| output_weight(script)).sum();
+    VERSION_WEIGHT + LOCKTIME_WEIGHT + SEGWIT_HEADER_WEIGHT + 4 * (1 + output_count) + outputs
+}
+
+/// The BIP44 coin type: 0' for mainnet, 1' for every test network.
+pub fn coin_type(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    }
+}
+
+/// The Native Segwit account path for the network, e.g. m/84h/1h/0h on signet.
+pub fn account_path(network: Network) -> String {
+    format!("m/84h/{}h/0h", coin_type(network))
+}
+
+/// The BIP86 Taproot account path for the network, e.g. m/86h/0h/0h on mainnet.
+pub fn taproot_account_path(network: Network) -> String {
+    format!("m/86h/{}h/0h", coin_type(network))
+}
+
+/// The BIP48 Native Segwit multisig account path, e.g. m/48h/0h/0h/2h on mainnet.
+pub fn multisig_account_path(network: Network) -> String {
+    format!("m/48h/{}h/0h/2h", coin_type(network))
+}
+
+/// Builds an m-of-n `wsh(sortedmulti(...))` descriptor. Each cosigner is either `fingerprint:xpub`, which
+/// assumes the BIP48 account path, or a key with its origin such as `[fingerprint/48h/0h/0h/2h]xpub`.
+pub fn multisig_descriptor(threshold: usize, cosigners: &[String], network: Network) -> Result<String, Box<dyn Error>> {
+    if threshold < 1 || threshold > cosigners.len() {
+        return Err(io::Error::other(format!("A threshold of {} is not possible with {} cosigners", threshold, cosigners.len())).into());
+    }
+    let version = if network == Network::Bitcoin { Version::Xpub } else { Version::Tpub };
+    let mut keys = Vec::new();
+    for cosigner in cosigners {
+        let (origin, xpub) = if let Some((origin, xpub)) = cosigner.split_once(']') {
+            (format!("{}]", origin.replace('h', "'")), xpub)
+        } else if let Some((fp, xpub)) = cosigner.split_once(':') {
+            let fingerprint = Fingerprint::from_str(fp)?;
+            (format!("[{}/48'/{}'/0'/2']", fingerprint, coin_type(network)), xpub)
+        } else {
+            return Err(io::Error::other(format!("Could not read the cosigner {}", cosigner)).into());
+        };
+        let converted = convert_version(xpub, &version).map_err(|_| io::Error::other(format!("Could not read the extended public key {}", xpub)))?;
+        keys.push(format!("{}{}/<0;1>/*", origin, ExtendedPubKey::from_str(&converted)?));
+    }
+    Ok(format!("wsh(sortedmulti({},{}))", threshold, keys.join(",")))
+}
+
+/// Builds the Native Segwit descriptor for a single signer from a fingerprint and an account xpub, zpub, tpub or vpub.
+pub fn single_sig_descriptor(fp: &str, xpub: &str, network: Network) -> Result<String, Box<dyn Error>> {
+    let (fingerprint, xpub) = parse_account_key(fp, xpub, network)?;
+    Ok(format!("wpkh([{}/84'/{}'/0']{}/<0;1>/*)", fingerprint, coin_type(network), xpub))
+}
+
+/// Builds the BIP86 Taproot descriptor for a single signer from a fingerprint and an account xpub or tpub.
+pub fn taproot_descriptor(fp: &str, xpub: &str, network: Network) -> Result<String, Box<dyn Error>> {
+    let (fingerprint, xpub) = parse_account_key(fp, xpub, network)?;
+    Ok(format!("tr([{}/86'/{}'/0']{}/<0;1>/*)", fingerprint, coin_type(network), xpub))
+}
+
+fn parse_account_key(fp: &str, xpub: &str, network: Network) -> Result<(Fingerprint, ExtendedPubKey), Box<dyn Error>> {
+    let fingerprint = Fingerprint::from_str(fp)?;
+    let version = if network == Network::Bitcoin { Version::Xpub } else { Version::Tpub };
+    let converted = convert_version(xpub, &version).map_err(|_| io::Error::other("Could not read the extended public key"))?;
+    Ok((fingerprint, ExtendedPubKey::from_str(&converted)?))
+}
+
+pub fn compute_address(wallet: &WalletDescriptor, external: bool, ind: u32) -> Result<Address, Box<dyn Error>>{
+    let address = wallet.derive(external, ind)?.address(wallet.network)?;
     Ok(address)
 }
 
-pub fn compute_address_info(xpub: &str, external: bool, ind: u32) -> Result<AddressInfo, Box<dyn Error>> {
-    let network = bitcoin::Network::Bitcoin;
-    let mut buf: Vec<AlignedType> = Vec::new();
-    buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
-    let secp = Secp256k1::preallocated_new(buf.as_mut_slice())?;
-    // standardize zpub to xpub
-    let result = convert_version(xpub, &Version::Xpub).expect("error converting xpub");
-    let root = ExtendedPubKey::from_str(&result)?;
-    let is_external = if external { 0 } else { 1 };
-    let internal = ChildNumber::from_normal_idx(is_external)?;
-    let index = ChildNumber::from_normal_idx(ind)?;
-    let public_key = root.derive_pub(&secp, &[internal, index])?.public_key;
-
-    let address = Address::p2wpkh(&PublicKey::new(public_key), network)?;
-    let path = format!("m/84h/0h/0h/{}/{}", is_external, index); // update
-    Ok(AddressInfo { address, path_to: DerivationPath::from_str(path.to_string().as_str())?, public_key })
-}
-
-pub fn compute_script_pubkey(xpub: &str, external: bool, ind: u32) -> Result<ScriptBuf, Box<dyn Error>>{
-    // initialize the eliptic curve
-    let network = bitcoin::Network::Bitcoin;
-    let mut buf: Vec<AlignedType> = Vec::new();
-    buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
-    let secp = Secp256k1::preallocated_new(buf.as_mut_slice())?;
-    // standardize zpub to xpub
-    let result = convert_version(xpub, &Version::Xpub).expect("error converting xpub");
-    let root = ExtendedPubKey::from_str(&result)?;
-    let is_external = if external { 0 } else { 1 };
-    let internal = ChildNumber::from_normal_idx(is_external)?;
-    let index = ChildNumber::from_normal_idx(ind)?;
-    let public_key = root.derive_pub(&secp, &[internal, index])?.public_key;
-
-    let script_buf = Address::p2wpkh(&PublicKey::new(public_key), network)?.script_pubkey();
+pub fn compute_address_info(wallet: &WalletDescriptor, external: bool, ind: u32) -> Result<AddressInfo, Box<dyn Error>> {
+    let address = compute_address(wallet, external, ind)?;
+    Ok(AddressInfo { address, external, index: ind })
+}
+
+pub fn compute_script_pubkey(wallet: &WalletDescriptor, external: bool, ind: u32) -> Result<ScriptBuf, Box<dyn Error>>{
+    let script_buf = wallet.derive(external, ind)?.script_pubkey();
     Ok(script_buf)
 }
 
-pub fn is_valid_fp(fp: &String) -> bool {
+pub fn is_valid_fp(fp: &str) -> bool {
     let master_fp = Fingerprint::from_str(fp);
-    if !master_fp.is_ok() {
+    if master_fp.is_err() {
         return false;
     }
     println!("\nFingerprint valid\n"); 
-    return true;
+    true
 }
 
-pub fn is_valid_xpub(xpub: &String) -> bool {
+pub fn is_valid_xpub(xpub: &str, network: Network) -> bool {
+    // xpub and zpub belong to mainnet, tpub and vpub to testnet, signet and regtest
+    let prefixes = if network == Network::Bitcoin { ["xpub", "zpub"] } else { ["tpub", "vpub"] };
+    if !prefixes.iter().any(|prefix| xpub.starts_with(prefix)) {
+        return false;
+    }
     let result = convert_version(xpub, &Version::Xpub);
-    if !result.is_ok() {
+    if result.is_err() {
         return false;
     }
     let xpub = ExtendedPubKey::from_str(result.unwrap().as_str());
-    if !xpub.is_ok() {
+    if xpub.is_err() {
         return false;
     } 
     println!("XPUB valid\n");
-    return true;
+    true
 }
 
-pub fn select_coins(mut coins: Vec<SelectionUTXO>, mut target: f64, per_byte_fee: f64, smallest: bool) -> Result<(Vec<SelectionUTXO>, f64), Box<dyn Error>> {
-    target += (VERSION_BYTE_FEE + LOCKTIME_BYTE_FEE + INPUT_COUNTER_BYTE_FEE + OUTPUT_COUNTER_BYTE_FEE) * per_byte_fee;
-    print!(" Sorting coins... ");
-    //most expensive part of the algorithm
-    if smallest {
-        coins.par_sort_unstable_by(|a, b| a.value.partial_cmp(&b.value).expect("Some error when comparing values"));
-    } else {
-        coins.par_sort_unstable_by(|a, b| b.value.partial_cmp(&a.value).expect("Some error when comparing values"));
-    }
-    //tally the amount
-    let mut amount = 0.;
-    let mut selected = Vec::new();
-    //if the coins vector is empty we could not reach the target
-    while !coins.is_empty() {
-        let coin = coins.remove(0);
-        target += INPUT_BYTE_FEE * per_byte_fee / 1.5; //we have to adjust this each time there is an input added. div to estimate Segwit discount
-        amount += coin.value;
-        selected.push(coin);
-        if amount > target {
-            return Ok((selected, amount - target)); //return the vector and change amount
-        } else if target > amount {
-            continue;
-        } else {
-            return Ok((selected, 0.));
-        }
+/// Selects coins to pay the target with the named algorithm, returning them with the change, which is zero when no change output is needed.
+pub fn select_coins(coins: Vec<SelectionUTXO>, params: &SelectionParams, algorithm: &str) -> Result<(Vec<SelectionUTXO>, u64), Box<dyn Error>> {
+    print!(" Selecting coins... ");
+    let selection = match algorithm {
+        "largest" => largest_first(&coins, params),
+        "smallest" => smallest_first(&coins, params),
+        "bnb" => branch_and_bound(&coins, params),
+        "random" => single_random_draw(&coins, params),
+        "waste" => least_waste(&coins, params),
+        _ => return Err(Box::new(io::Error::other(format!("Unrecognized coin selection algorithm. Options are [{}]", ALGORITHMS.join(", "))))),
+    };
+    let Some(selection) = selection else {
+        println!("Not enough coins to make this transcation!");
+        let err = io::Error::other("Insufficient balance");
+        return Err(Box::new(err));
+    };
+    let chosen: Vec<&SelectionUTXO> = selection.iter().map(|i| &coins[*i]).collect();
+    let change = params.change(&chosen).ok_or(io::Error::other("The selected coins do not cover the payments and fees"))?;
+    let mut coins: Vec<Option<SelectionUTXO>> = coins.into_iter().map(Some).collect();
+    let selected = selection.iter().filter_map(|i| coins[*i].take()).collect();
+    Ok((selected, change))
+}
+
+/// The locktime for a new transaction: the current height, so it cannot be mined in a reorganization of earlier
+/// blocks. Like Bitcoin Core, one in ten is set up to 100 blocks back so transactions that were delayed do not stand out.
+pub fn anti_fee_sniping(tip: u32) -> absolute::LockTime {
+    let mut rng = rand::thread_rng();
+    let mut height = tip;
+    if rng.gen_range(0..10) == 0 {
+        height = height.saturating_sub(rng.gen_range(0..100));
     }
-    println!("Not enough coins to make this transcation!");
-    let err = io::Error::new(ErrorKind::Other, "Insufficient balance");
-    Err(Box::new(err))
+    absolute::LockTime::from_height(height).unwrap_or(absolute::LockTime::ZERO)
 }
 
-fn create_and_update_psbt(selected_utxo: Vec<SelectionUTXO>, xpub: ExtendedPubKey, finger_print: Fingerprint, receive_addr: Address, change_addr: AddressInfo, change: u64, amount: u64) -> Result<Psbt, Box<dyn Error>> {  
-    print!("Creating your transaction...\n");
+fn create_and_update_psbt(selected_utxo: Vec<SelectionUTXO>, wallet: &WalletDescriptor, payments: Vec<(Address, u64)>, change_addr: AddressInfo, change: u64, lock_time: absolute::LockTime, sequence: Sequence) -> Result<Psbt, Box<dyn Error>> {  
+    println!("Creating your transaction...");
     
     let mut input = Vec::new();
-    let mut output = Vec::new();
-
-    output.extend(vec![
-        TxOut { value: amount, script_pubkey: receive_addr.script_pubkey() },
-        TxOut { value: change, script_pubkey: change_addr.address.script_pubkey() },
-    ]);
+    let mut output: Vec<TxOut> = payments.iter().map(|(address, amount)| TxOut { value: *amount, script_pubkey: address.script_pubkey() }).collect();
+    if change > 0 {
+        output.push(TxOut { value: change, script_pubkey: change_addr.address.script_pubkey() });
+    }
 
     for utxo in &selected_utxo {
         input.push(
             TxIn {
                 previous_output: OutPoint { txid: utxo.id , vout: utxo.index as u32 },
                 script_sig: ScriptBuf::new(),
-                sequence: Sequence::MAX, 
+                sequence,
                 witness: Witness::default(),
 
             }
@@ -176,110 +377,752 @@ fn create_and_update_psbt(selected_utxo: Vec<SelectionUTXO>, xpub: ExtendedPubKe
 
     let tx = Transaction {
         version: 2,
-        lock_time: absolute::LockTime::ZERO,
+        lock_time,
         input,
         output,
     };
 
     let mut psbt = Psbt::from_unsigned_tx(tx)?;
-    let path =IntoDerivationPath::into_derivation_path("m/84h/0h/0h")?; //hardcoded for single sig
-    let mut map = BTreeMap::new();
-    map.insert(xpub, (finger_print, path));
-    psbt.xpub = map;
-    let mut inputs = Vec::new();
-
-    for utxo in selected_utxo {
-        let mut input = Input { witness_utxo: Some(TxOut { value: utxo.value as u64, script_pubkey:  utxo.script.clone() }), ..Default::default() };
-        let path = utxo.info.path_to;
-        let mut map = BTreeMap::new();
-        map.insert(utxo.info.public_key, (finger_print, path));
-        input.bip32_derivation = map;
-        let ty = PsbtSighashType::from_str("SIGHASH_ALL")?;
-        input.sighash_type = Some(ty);
-        inputs.push(input)
-    }
+    psbt.xpub = wallet.xpubs();
 
-    let mut out_map = BTreeMap::new();
-    out_map.insert(change_addr.public_key, (finger_print, change_addr.path_to));
-    let out = Output { bip32_derivation: out_map, ..Default::default() };
+    // the descriptor fills in the key origins and scripts each signer needs
+    for (i, utxo) in selected_utxo.iter().enumerate() {
+        psbt.inputs[i].witness_utxo = Some(TxOut { value: utxo.value, script_pubkey: utxo.script.clone() });
+        // taproot signs with the default sighash, which commits to the same data as SIGHASH_ALL
+        if !wallet.is_taproot() {
+            psbt.inputs[i].sighash_type = Some(PsbtSighashType::from_str("SIGHASH_ALL")?);
+        }
+        let descriptor = wallet.derive(utxo.info.external, utxo.info.index)?;
+        psbt.update_input_with_descriptor(i, &descriptor)?;
+    }
 
-    psbt.inputs = inputs;
-    psbt.outputs = vec![Output { ..Default::default() }, out];
+    if change > 0 {
+        let change_descriptor = wallet.derive(false, change_addr.index)?;
+        psbt.update_output_with_descriptor(payments.len(), &change_descriptor)?;
+    }
     Ok(psbt)
 
 }
 
-pub fn make_and_download_transaction(target: u64, xpub: &str, fp: &str, receive_addr: &str, block_target: usize, clean_wallet: bool, client: &str) -> Result<(), Box<dyn Error>> {
-    let new_psbt = make(target, xpub, fp, receive_addr, block_target, clean_wallet, client).expect("Error Occured Making PSBT");
-    let psbt_bytes = new_psbt.serialize();
-    let file_name = "unsigned.psbt".bright_blue();
+/// Saves a PSBT with the given file name in the downloads folder.
+pub fn download_psbt(psbt: &Psbt, name: &str) -> Result<(), Box<dyn Error>> {
+    let psbt_bytes = psbt.serialize();
+    let file_name = name.bright_blue();
     let downloads = "downloads".bright_green();
     println!("\nYour transcation as been saved as {file_name} in your {downloads} folder!\n");
-    let mut psbt_file = File::create(dirs::download_dir().unwrap().join("unsigned.psbt")).expect("error making file");
+    let mut psbt_file = File::create(dirs::download_dir().unwrap().join(name)).expect("error making file");
     psbt_file.write_all(&psbt_bytes).expect("error writing bytes");
     Ok(())
 }
 
-pub fn make_and_send_to_ledger(target: u64, xpub: &str, fp: &str, receive_addr: &str, block_target: usize, clean_wallet: bool, client: &str) -> Result<Psbt, Box<dyn Error>> {
-    let ledger = make(target, xpub, fp, receive_addr, block_target, clean_wallet, client).expect("Error Occured Making PSBT");
-    Ok(ledger)
+#[allow(clippy::too_many_arguments)]
+pub fn make_and_download_transaction(recipients: &[Recipient], coin_control: &CoinControl, options: &TxOptions, wallet: &WalletDescriptor, cache: &mut WalletCache, block_target: usize, algorithm: &str, source: &dyn ChainSource) -> Result<(), Box<dyn Error>> {
+    let new_psbt = make(recipients, coin_control, options, wallet, cache, block_target, algorithm, source)?;
+    download_psbt(&new_psbt, "unsigned.psbt")
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn make_and_send_to_ledger(recipients: &[Recipient], coin_control: &CoinControl, options: &TxOptions, wallet: &WalletDescriptor, cache: &mut WalletCache, block_target: usize, algorithm: &str, source: &dyn ChainSource) -> Result<Psbt, Box<dyn Error>> {
+    make(recipients, coin_control, options, wallet, cache, block_target, algorithm, source)
 }
 
-fn make(target: u64, xpub: &str, fp: &str, receive_addr: &str, block_target: usize, clean_wallet: bool, client: &str) -> Result<Psbt, Box<dyn Error>> {
-    let receive = Address::from_str(receive_addr).expect("error getting address from string").require_network(bitcoin::Network::Bitcoin).expect("error getting address from network");
-    let result = convert_version(xpub, &Version::Xpub).expect("error converting xpub");
-    let root = ExtendedPubKey::from_str(&result)?;
-    let master_fp = Fingerprint::from_str(fp)?;
-    let target_to_float = target as f64;
-    let byte_fee = get_fee_estimate(block_target, client)?;
-    let available_coins = get_all_utxo(xpub, 5, client)?;
-    let change_addr = get_fresh_change(xpub, client)?;
-    let (coins, change ) = select_coins(available_coins, target_to_float, byte_fee, clean_wallet)?;
-    let psbt = create_and_update_psbt(coins, root, master_fp, receive, change_addr, change as u64, target)?;
+fn selection_params(wallet: &WalletDescriptor, recipients: &[ScriptBuf], target: u64, fee_rate: f64, source: &dyn ChainSource) -> Result<SelectionParams, Box<dyn Error>> {
+    Ok(SelectionParams {
+        target,
+        fee_rate,
+        long_term_fee_rate: get_fee_estimate(LONG_TERM_BLOCKS, source)?,
+        base_weight: base_weight(recipients),
+        input_weight: wallet.input_weight()?,
+        change_weight: wallet.change_weight()?,
+        change_dust: wallet.derive(false, 0)?.script_pubkey().dust_value().to_sat(),
+    })
+}
+
+/// Builds the transaction paying every recipient. When one recipient has no amount, it receives everything
+/// left in the wallet after the other payments and the fee, and there is no change.
+#[allow(clippy::too_many_arguments)]
+fn make(recipients: &[Recipient], coin_control: &CoinControl, options: &TxOptions, wallet: &WalletDescriptor, cache: &mut WalletCache, block_target: usize, algorithm: &str, source: &dyn ChainSource) -> Result<Psbt, Box<dyn Error>> {
+    if recipients.iter().filter(|recipient| recipient.amount.is_none()).count() > 1 {
+        return Err(Box::new(io::Error::other("Only one recipient can receive the maximum")));
+    }
+    let mut addresses = Vec::new();
+    for recipient in recipients {
+        addresses.push(Address::from_str(&recipient.address)?.require_network(wallet.network)?);
+    }
+    let scripts: Vec<ScriptBuf> = addresses.iter().map(|address| address.script_pubkey()).collect();
+    let byte_fee = get_fee_estimate(block_target, source)?;
+    let target = recipients.iter().filter_map(|recipient| recipient.amount).sum();
+    let params = selection_params(wallet, &scripts, target, byte_fee, source)?;
+    let mut available_coins = get_all_utxo(wallet, cache)?;
+    available_coins.retain(|coin| !coin_control.exclude.contains(&coin.outpoint()));
+    if let Some(missing) = coin_control.include.iter().find(|outpoint| !available_coins.iter().any(|coin| coin.outpoint() == **outpoint)) {
+        return Err(Box::new(io::Error::other(format!("{missing} is not an unspent output this wallet can spend"))));
+    }
+    if !coin_control.include.is_empty() {
+        available_coins.retain(|coin| coin_control.include.contains(&coin.outpoint()));
+    }
+    let change_addr = get_fresh_change(wallet, cache)?;
+    let (coins, change, remainder) = if recipients.iter().all(|recipient| recipient.amount.is_some()) && !coin_control.include.is_empty() {
+        let chosen: Vec<&SelectionUTXO> = available_coins.iter().collect();
+        let change = params.change(&chosen).ok_or(io::Error::other("The chosen coins do not cover the payments and fee"))?;
+        (available_coins, change, 0)
+    } else if recipients.iter().all(|recipient| recipient.amount.is_some()) {
+        let (coins, change) = select_coins(available_coins, &params, algorithm)?;
+        (coins, change, 0)
+    } else {
+        let total: u64 = available_coins.iter().map(|coin| coin.value).sum();
+        let spent = params.target + params.fee(available_coins.len(), false);
+        let dust = recipients.iter().zip(&scripts).find(|(recipient, _)| recipient.amount.is_none()).map_or(0, |(_, script)| script.dust_value().to_sat());
+        if total < spent + dust {
+            return Err(Box::new(io::Error::other("The balance is too small to pay the fee")));
+        }
+        (available_coins, 0, total - spent)
+    };
+    let payments = recipients.iter().zip(addresses).map(|(recipient, address)| (address, recipient.amount.unwrap_or(remainder))).collect();
+    let lock_time = options.lock_time.unwrap_or(anti_fee_sniping(cache.tip));
+    let psbt = create_and_update_psbt(coins, wallet, payments, change_addr, change, lock_time, options.sequence.unwrap_or(Sequence::ENABLE_RBF_NO_LOCKTIME))?;
     Ok(psbt)
 }
 
-pub fn print_psbt(psbt: Psbt) -> Result<(), Box<dyn Error>> {
-    let val = psbt.unsigned_tx.output[0].value.to_string().bright_green();
-    // assumes the first output is the spending address
-    let btc_val = psbt.unsigned_tx.output[0].value as f64 / 100_000_000.;
-    let addr = psbt.unsigned_tx.output[0].script_pubkey.as_script();
-    let address = Address::from_script(addr, bitcoin::Network::Bitcoin)?;
-    let color_address = address.to_string().bright_green();
-    println!("You are sending {} Satoshis [{} Bitcoin] to {}", val, btc_val, color_address);
-    let fee = psbt.fee()?.to_string().bright_green();
-    println!("There is a fee of {}\n", fee);
+/// Builds a BIP125 replacement for an unconfirmed transaction sent from this wallet. The recipients are paid the
+/// same amounts at a higher fee rate, taken from the change first, with more coins added when the change is not enough.
+pub fn make_bump(txid: Txid, wallet: &WalletDescriptor, cache: &mut WalletCache, block_target: usize, frozen: &[OutPoint], source: &dyn ChainSource) -> Result<Psbt, Box<dyn Error>> {
+    let tx = replaceable(txid, cache, source)?;
+    let (spent, old_fee) = wallet_inputs(&tx, wallet, cache, source)?;
+    let owners = cache.owners();
+    let mut payments = Vec::new();
+    let mut change_index = None;
+    for output in &tx.output {
+        match owners.get(&output.script_pubkey) {
+            Some((false, index)) => change_index = Some(*index),
+            _ => payments.push((Address::from_script(&output.script_pubkey, wallet.network)?, output.value)),
+        }
+    }
+    let change_addr = match change_index {
+        Some(index) => compute_address_info(wallet, false, index)?,
+        None => get_fresh_change(wallet, cache)?,
+    };
+    let fee_rate = replacement_fee_rate(&tx, old_fee, block_target, source)?;
+    let scripts: Vec<ScriptBuf> = payments.iter().map(|(address, _)| address.script_pubkey()).collect();
+    let params = selection_params(wallet, &scripts, payments.iter().map(|(_, amount)| amount).sum(), fee_rate, source)?;
+    let mut coins = spent;
+    let mut available = get_all_utxo(wallet, cache)?;
+    // BIP125 forbids a replacement from adding unconfirmed inputs
+    let confirmed: Vec<OutPoint> = cache.utxos.iter().filter(|utxo| utxo.height > 0).map(|utxo| utxo.outpoint).collect();
+    available.retain(|coin| confirmed.contains(&coin.outpoint()) && !frozen.contains(&coin.outpoint()));
+    available.sort_by_key(|coin| std::cmp::Reverse(coin.value));
+    let mut available = available.into_iter();
+    let change = loop {
+        let chosen: Vec<&SelectionUTXO> = coins.iter().collect();
+        if let Some(change) = params.change(&chosen) {
+            break change;
+        }
+        coins.push(available.next().ok_or(io::Error::other("There are not enough coins to pay the higher fee"))?);
+    };
+    let total: u64 = coins.iter().map(|coin| coin.value).sum();
+    let fee = total - params.target - change;
+    if (fee as f64) < old_fee as f64 + params.vsize(coins.len(), change > 0) as f64 * INCREMENTAL_RELAY_FEE_RATE {
+        return Err(Box::new(io::Error::other("The replacement does not pay enough more than the original. Try a higher fee rate")));
+    }
+    create_and_update_psbt(coins, wallet, payments, change_addr, change, anti_fee_sniping(cache.tip), Sequence::ENABLE_RBF_NO_LOCKTIME)
+}
+
+/// Builds a BIP125 replacement for an unconfirmed transaction sent from this wallet that spends the same coins
+/// back to a fresh change address, so the payment never happens.
+pub fn make_cancel(txid: Txid, wallet: &WalletDescriptor, cache: &mut WalletCache, block_target: usize, source: &dyn ChainSource) -> Result<Psbt, Box<dyn Error>> {
+    let tx = replaceable(txid, cache, source)?;
+    let (coins, old_fee) = wallet_inputs(&tx, wallet, cache, source)?;
+    let fee_rate = replacement_fee_rate(&tx, old_fee, block_target, source)?;
+    let params = selection_params(wallet, &[], 0, fee_rate, source)?;
+    let vsize = params.vsize(coins.len(), true);
+    let fee = params.fee(coins.len(), true).max(old_fee + (vsize as f64 * INCREMENTAL_RELAY_FEE_RATE).ceil() as u64);
+    let total: u64 = coins.iter().map(|coin| coin.value).sum();
+    if total < fee + params.change_dust {
+        return Err(Box::new(io::Error::other("The coins are too small to pay the higher fee")));
+    }
+    let change_addr = get_fresh_change(wallet, cache)?;
+    create_and_update_psbt(coins, wallet, Vec::new(), change_addr, total - fee, anti_fee_sniping(cache.tip), Sequence::ENABLE_RBF_NO_LOCKTIME)
+}
+
+/// An unconfirmed transaction that signals replace-by-fee, as the backend has it.
+fn replaceable(txid: Txid, cache: &WalletCache, source: &dyn ChainSource) -> Result<Transaction, Box<dyn Error>> {
+    let tx = source.transactions(&[txid])?.pop().ok_or(io::Error::other(format!("The transaction {txid} was not found")))?;
+    if cache.transactions.get(&txid).is_some_and(|cached| cached.height > 0) {
+        return Err(Box::new(io::Error::other("The transaction is already confirmed")));
+    }
+    if !tx.is_explicitly_rbf() {
+        return Err(Box::new(io::Error::other("The transaction does not signal replace-by-fee")));
+    }
+    Ok(tx)
+}
+
+/// The fee rate for the block target, raised so a replacement pays at least the incremental relay fee more than the original.
+fn replacement_fee_rate(tx: &Transaction, old_fee: u64, block_target: usize, source: &dyn ChainSource) -> Result<f64, Box<dyn Error>> {
+    let old_rate = old_fee as f64 / tx.vsize() as f64;
+    Ok(get_fee_estimate(block_target, source)?.max(old_rate + INCREMENTAL_RELAY_FEE_RATE))
+}
+
+/// Builds a transaction merging the wallet's coins worth less than `below`, or all of them, into one fresh change
+/// output at the given fee rate. Prints what it saves compared to spending the coins one by one at `future_rate` later.
+pub fn make_consolidation(wallet: &WalletDescriptor, cache: &mut WalletCache, fee_rate: f64, future_rate: f64, below: Option<u64>, frozen: &[OutPoint], source: &dyn ChainSource) -> Result<Psbt, Box<dyn Error>> {
+    let mut coins = get_all_utxo(wallet, cache)?;
+    coins.retain(|coin| !frozen.contains(&coin.outpoint()) && below.is_none_or(|below| coin.value < below));
+    if coins.len() < 2 {
+        return Err(Box::new(io::Error::other("There are not at least two coins to consolidate")));
+    }
+    let params = selection_params(wallet, &[], 0, fee_rate, source)?;
+    let fee = params.fee(coins.len(), true);
+    let total: u64 = coins.iter().map(|coin| coin.value).sum();
+    if total < fee + params.change_dust {
+        return Err(Box::new(io::Error::other("The coins are too small to pay the fee")));
+    }
+    // later, each coin would be spent at the future rate, while after consolidating only the one output is
+    let input_cost = params.input_weight as f64 / 4. * future_rate;
+    let savings = (coins.len() as f64 - 1.) * input_cost - fee as f64;
+    let count = coins.len().to_string().bright_blue();
+    println!("\nConsolidating {} coins worth {} Satoshis for a fee of {} Satoshis", count, total, fee.to_string().bright_blue());
+    if savings > 0. {
+        let saved = format!("{:.0}", savings).bright_green();
+        println!("Compared to spending them separately at {} sat/vB, this saves about {} Satoshis\n", future_rate, saved);
+    } else {
+        let warn = format!("Spending them separately at {} sat/vB would cost {:.0} Satoshis less. Consider a lower fee rate\n", future_rate, -savings).bright_yellow();
+        println!("{warn}");
+    }
+    let change_addr = get_fresh_change(wallet, cache)?;
+    create_and_update_psbt(coins, wallet, Vec::new(), change_addr, total - fee, anti_fee_sniping(cache.tip), Sequence::ENABLE_RBF_NO_LOCKTIME)
+}
+
+/// Builds a child transaction spending the wallet's outputs of an unconfirmed incoming transaction back to the
+/// wallet, paying enough that the parent and child together reach the fee rate for the block target.
+pub fn make_cpfp(txid: Txid, wallet: &WalletDescriptor, cache: &mut WalletCache, block_target: usize, frozen: &[OutPoint], source: &dyn ChainSource) -> Result<Psbt, Box<dyn Error>> {
+    if cache.transactions.get(&txid).is_none_or(|cached| cached.height > 0) {
+        return Err(Box::new(io::Error::other("The transaction is not an unconfirmed transaction of this wallet")));
+    }
+    let parent = cache.transaction(&txid).ok_or(io::Error::other("A cached transaction could not be read"))?;
+    let coins: Vec<SelectionUTXO> = get_all_utxo(wallet, cache)?.into_iter().filter(|coin| coin.id == txid && !frozen.contains(&coin.outpoint())).collect();
+    if coins.is_empty() {
+        return Err(Box::new(io::Error::other("The transaction has no unspent outputs for this wallet that are not frozen")));
+    }
+    let parent_fee = match source.mempool_fee(&txid)? {
+        Some(fee) => fee,
+        None => {
+            let mut spent = 0;
+            for input in &parent.input {
+                let previous = match cache.transaction(&input.previous_output.txid) {
+                    Some(previous) => previous,
+                    None => source.transactions(&[input.previous_output.txid])?.pop().ok_or(io::Error::other("A spent transaction was not found"))?,
+                };
+                spent += previous.output.get(input.previous_output.vout as usize).ok_or(io::Error::other("A spent output does not exist"))?.value;
+            }
+            spent.saturating_sub(parent.output.iter().map(|output| output.value).sum())
+        },
+    };
+    let fee_rate = get_fee_estimate(block_target, source)?;
+    let params = selection_params(wallet, &[], 0, fee_rate, source)?;
+    let package_vsize = parent.vsize() as u64 + params.vsize(coins.len(), true);
+    let fee = ((package_vsize as f64 * fee_rate).ceil() as u64).saturating_sub(parent_fee).max(params.fee(coins.len(), true));
+    let total: u64 = coins.iter().map(|coin| coin.value).sum();
+    if total < fee + params.change_dust {
+        return Err(Box::new(io::Error::other("The outputs are too small to pay for the child transaction")));
+    }
+    let change_addr = get_fresh_change(wallet, cache)?;
+    create_and_update_psbt(coins, wallet, Vec::new(), change_addr, total - fee, anti_fee_sniping(cache.tip), Sequence::ENABLE_RBF_NO_LOCKTIME)
+}
+
+/// The wallet's coins spent by a transaction and the fee it pays. Every input must belong to the wallet.
+fn wallet_inputs(tx: &Transaction, wallet: &WalletDescriptor, cache: &WalletCache, source: &dyn ChainSource) -> Result<(Vec<SelectionUTXO>, u64), Box<dyn Error>> {
+    let owners = cache.owners();
+    let mut coins = Vec::new();
+    for input in &tx.input {
+        let previous = match cache.transaction(&input.previous_output.txid) {
+            Some(previous) => previous,
+            None => source.transactions(&[input.previous_output.txid])?.pop().ok_or(io::Error::other("A spent transaction was not found"))?,
+        };
+        let output = previous.output.get(input.previous_output.vout as usize).ok_or(io::Error::other("A spent output does not exist"))?;
+        let Some((external, index)) = owners.get(&output.script_pubkey) else {
+            return Err(Box::new(io::Error::other("Only transactions spending this wallet's coins alone can be replaced")));
+        };
+        let info = compute_address_info(wallet, *external, *index)?;
+        coins.push(SelectionUTXO { id: input.previous_output.txid, index: input.previous_output.vout as usize, value: output.value, script: output.script_pubkey.clone(), info });
+    }
+    let spent: u64 = coins.iter().map(|coin| coin.value).sum();
+    let paid: u64 = tx.output.iter().map(|output| output.value).sum();
+    Ok((coins, spent.saturating_sub(paid)))
+}
+
+/// The size in virtual bytes of the signed transaction, estimated as coin selection does so the
+/// rate shown matches the one the fee was chosen for, signed or not.
+pub fn estimated_vsize(psbt: &Psbt, wallet: &WalletDescriptor) -> Result<u64, Box<dyn Error>> {
+    let scripts: Vec<ScriptBuf> = psbt.unsigned_tx.output.iter().map(|output| output.script_pubkey.clone()).collect();
+    let (last, recipients) = scripts.split_last().ok_or(io::Error::other("The transaction has no outputs"))?;
+    let params = SelectionParams {
+        target: 0,
+        fee_rate: 0.,
+        long_term_fee_rate: 0.,
+        base_weight: base_weight(recipients),
+        input_weight: wallet.input_weight()?,
+        change_weight: output_weight(last),
+        change_dust: 0,
+    };
+    Ok(params.vsize(psbt.inputs.len(), true))
+}
+
+pub fn print_psbt(psbt: Psbt, wallet: &WalletDescriptor, network: Network) -> Result<(), Box<dyn Error>> {
+    // change outputs carry the key origins of this wallet, every other output is a recipient
+    let mut total = 0;
+    let mut recipients = 0;
+    for (output, details) in psbt.unsigned_tx.output.iter().zip(&psbt.outputs) {
+        if !details.bip32_derivation.is_empty() || !details.tap_key_origins.is_empty() {
+            continue;
+        }
+        let val = output.value.to_string().bright_green();
+        let btc_val = output.value as f64 / 100_000_000.;
+        let address = Address::from_script(&output.script_pubkey, network)?;
+        let color_address = address.to_string().bright_green();
+        println!("You are sending {} Satoshis [{} Bitcoin] to {}", val, btc_val, color_address);
+        total += output.value;
+        recipients += 1;
+    }
+    if recipients == 0 {
+        println!("Every output returns to your wallet");
+    } else if recipients > 1 {
+        let val = total.to_string().bright_green();
+        println!("In total you are sending {} Satoshis [{} Bitcoin] to {} recipients", val, total as f64 / 100_000_000., recipients);
+    }
+    let fee = psbt.fee()?;
+    let rate = format!("{:.2}", fee.to_sat() as f64 / estimated_vsize(&psbt, wallet)? as f64).bright_green();
+    let fee = fee.to_sat().to_string().bright_green();
+    println!("There is a fee of {} Satoshis, or {} sat/vB\n", fee, rate);
     Ok(())
 }
 
-pub fn extract_broadcast(mut psbt: Psbt, client: &str) -> Result<(), Box<dyn Error>> {
+/// Merges PSBTs for the same transaction signed by different cosigners.
+pub fn combine_psbts(psbts: Vec<Psbt>) -> Result<Psbt, Box<dyn Error>> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts.next().ok_or(io::Error::other("No PSBTs to combine"))?;
+    for psbt in psbts {
+        combined.combine(psbt)?;
+    }
+    Ok(combined)
+}
+
+/// Prints how many of the required signatures each input has.
+pub fn print_signatures(psbt: &Psbt, wallet: &WalletDescriptor) {
+    let (m, n) = wallet.threshold();
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        let signed = if input.final_script_witness.is_some() { m } else { input.partial_sigs.len() + input.tap_key_sig.iter().count() };
+        let count = format!("{} of {}", signed.min(m), m);
+        let count = if signed >= m { count.bright_green() } else { count.bright_yellow() };
+        println!("Input {}: {} signatures from {} cosigners", i, count, n);
+    }
+}
+
+/// Whether every input has enough signatures to be finalized.
+pub fn is_fully_signed(psbt: &Psbt) -> bool {
+    let mut buf: Vec<AlignedType> = Vec::new();
+    buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
+    let signed = match Secp256k1::preallocated_new(buf.as_mut_slice()) {
+        Ok(secp) => psbt.clone().finalize_mut(&secp).is_ok(),
+        Err(_) => false,
+    };
+    signed
+}
+
+pub fn extract_broadcast(mut psbt: Psbt, source: &dyn ChainSource, network: Network) -> Result<(), Box<dyn Error>> {
     if psbt.inputs.is_empty() {
         return Err(psbt::SignError::MissingInputUtxo.into());
     }
-    // if there are partial sigs we need to finalize
-    if !psbt.inputs[0].partial_sigs.is_empty() {
-        let network = bitcoin::Network::Bitcoin;
-        let mut buf: Vec<AlignedType> = Vec::new();
-        buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
-        let secp = Secp256k1::preallocated_new(buf.as_mut_slice())?;
-        psbt = Psbt::finalize(psbt, &secp).expect("Finalization error");
-    }
-    //extract the transaction (witness) from the PSBT
-    let network = bitcoin::Network::Bitcoin;
     let mut buf: Vec<AlignedType> = Vec::new();
     buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
     let secp = Secp256k1::preallocated_new(buf.as_mut_slice())?;
+    // signed inputs need their witness assembled, which fails for a multisig below its threshold
+    if psbt.inputs.iter().any(|input| input.final_script_witness.is_none()) {
+        psbt.finalize_mut(&secp).map_err(|_| io::Error::other("The transaction is missing signatures and cannot be finalized"))?;
+    }
+    //extract the transaction (witness) from the PSBT
     let ext = PsbtExt::extract(&psbt, &secp).expect("Error finalizing TX");
-    //send the transaction to the electrum client
-    let res = broadcast(ext, client)?;
+    //send the transaction to the configured backend
+    broadcast(ext, source, network)?;
     Ok(())
 }
 
 #[test]
 fn addresses() {
     let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
-    let address = compute_address(zpub, true, 2).unwrap();
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let address = compute_address(&wallet, true, 2).unwrap();
     assert_eq!(address.to_string(), "bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a");
-    let address = compute_address(zpub, true, 10).unwrap();
+    let address = compute_address(&wallet, true, 10).unwrap();
     assert_eq!(address.to_string(), "bc1qfh4ltu8ysfl9xq0ld88h88qja7sad283akey6w");
-}
\ No newline at end of file
+    assert_eq!(wallet.display_path(false, 3), "m/84'/0'/0'/1/3");
+    assert!(wallet.ledger_policy().ends_with("/**)"));
+}
+
+#[test]
+fn test_network_addresses() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let vpub = convert_version(zpub, &Version::Vpub).unwrap();
+    assert!(is_valid_xpub(zpub, Network::Bitcoin));
+    assert!(!is_valid_xpub(zpub, Network::Signet));
+    assert!(is_valid_xpub(&vpub, Network::Signet));
+    assert!(!is_valid_xpub(&vpub, Network::Bitcoin));
+    let mainnet_descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let signet_descriptor = single_sig_descriptor("7c7a8f2b", &vpub, Network::Signet).unwrap();
+    assert!(WalletDescriptor::new(&mainnet_descriptor, Network::Signet).is_err());
+    let mainnet = WalletDescriptor::new(&mainnet_descriptor, Network::Bitcoin).unwrap();
+    let signet = WalletDescriptor::new(&signet_descriptor, Network::Signet).unwrap();
+    let regtest = WalletDescriptor::new(&signet_descriptor, Network::Regtest).unwrap();
+    let mainnet_address = compute_address(&mainnet, true, 2).unwrap();
+    let signet_address = compute_address(&signet, true, 2).unwrap();
+    assert!(signet_address.to_string().starts_with("tb1q"));
+    assert_eq!(mainnet_address.script_pubkey(), signet_address.script_pubkey());
+    assert!(compute_address(&regtest, false, 3).unwrap().to_string().starts_with("bcrt1q"));
+    assert_eq!(regtest.display_path(false, 3), "m/84'/1'/0'/1/3");
+}
+
+#[test]
+fn test_multisig_combine() {
+    use bitcoin::bip32::ExtendedPrivKey;
+    let secp = Secp256k1::new();
+    let path = DerivationPath::from_str(&multisig_account_path(Network::Bitcoin)).unwrap();
+    let masters: Vec<ExtendedPrivKey> = (1..4).map(|seed| ExtendedPrivKey::new_master(Network::Bitcoin, &[seed; 32]).unwrap()).collect();
+    let cosigners: Vec<String> = masters.iter().map(|master| {
+        let xpub = ExtendedPubKey::from_priv(&secp, &master.derive_priv(&secp, &path).unwrap());
+        format!("{}:{}", master.fingerprint(&secp), xpub)
+    }).collect();
+    let descriptor = multisig_descriptor(2, &cosigners, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    assert_eq!(wallet.threshold(), (2, 3));
+    assert!(wallet.ledger_policy().starts_with("wsh(sortedmulti(2,["));
+    let info = compute_address_info(&wallet, true, 0).unwrap();
+    assert!(info.address.to_string().starts_with("bc1q"));
+    let utxo = SelectionUTXO { id: Txid::from_str("7d4a1ed9f64e3b0f3f5c8c5a4ef0b3a2b6c7b1d63f6cbbd1ad2be6e8a7e5f6a1").unwrap(), index: 0, value: 50_000, script: info.address.script_pubkey(), info };
+    let receive = compute_address(&wallet, true, 1).unwrap();
+    let change = compute_address_info(&wallet, false, 0).unwrap();
+    let psbt = create_and_update_psbt(vec![utxo], &wallet, vec![(receive, 29_000)], change, 20_000, absolute::LockTime::ZERO, Sequence::ENABLE_RBF_NO_LOCKTIME).unwrap();
+    assert_eq!(psbt.inputs[0].bip32_derivation.len(), 3);
+    assert!(psbt.inputs[0].witness_script.is_some());
+    assert_eq!(psbt.outputs[1].bip32_derivation.len(), 3);
+    let mut first = psbt.clone();
+    first.sign(&masters[0], &secp).unwrap();
+    let mut second = psbt.clone();
+    second.sign(&masters[2], &secp).unwrap();
+    assert!(!is_fully_signed(&first));
+    let combined = combine_psbts(vec![first, second]).unwrap();
+    assert!(is_fully_signed(&combined));
+    // the weight used for fees covers the signed transaction, with at most a byte to spare per signature
+    let mut finalized = combined;
+    finalized.finalize_mut(&secp).unwrap();
+    let tx = PsbtExt::extract(&finalized, &secp).unwrap();
+    let estimate = base_weight(&[tx.output[0].script_pubkey.clone()]) + wallet.input_weight().unwrap() + wallet.change_weight().unwrap();
+    assert!(estimate >= tx.weight().to_wu() && estimate - tx.weight().to_wu() <= 2);
+    // the size used for the fee rate is the same before and after signing
+    assert_eq!(estimated_vsize(&psbt, &wallet).unwrap(), estimated_vsize(&finalized, &wallet).unwrap());
+    assert_eq!(estimated_vsize(&finalized, &wallet).unwrap(), estimate.div_ceil(4));
+}
+
+#[test]
+fn test_taproot_psbt() {
+    // BIP86 test vector for the "abandon ... about" mnemonic
+    let xpub = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+    let descriptor = taproot_descriptor("73c5da0a", xpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    assert!(wallet.is_taproot());
+    let info = compute_address_info(&wallet, true, 0).unwrap();
+    assert_eq!(info.address.to_string(), "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr");
+    assert_eq!(compute_address(&wallet, false, 0).unwrap().to_string(), "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7");
+    // the 41 byte outpoint, sequence and script sig, the witness item count, and a Schnorr signature with its length and sighash byte
+    assert_eq!(wallet.input_weight().unwrap(), 164 + 1 + 66);
+    let utxo = SelectionUTXO { id: Txid::from_str("7d4a1ed9f64e3b0f3f5c8c5a4ef0b3a2b6c7b1d63f6cbbd1ad2be6e8a7e5f6a1").unwrap(), index: 1, value: 50_000, script: info.address.script_pubkey(), info };
+    let receive = compute_address(&wallet, true, 1).unwrap();
+    let change = compute_address_info(&wallet, false, 0).unwrap();
+    let psbt = create_and_update_psbt(vec![utxo.clone()], &wallet, vec![(receive.clone(), 29_000)], change.clone(), 20_000, absolute::LockTime::ZERO, Sequence::ENABLE_RBF_NO_LOCKTIME).unwrap();
+    assert!(psbt.inputs[0].tap_internal_key.is_some());
+    assert_eq!(psbt.inputs[0].tap_key_origins.len(), 1);
+    assert!(psbt.inputs[0].sighash_type.is_none());
+    assert!(psbt.outputs[1].tap_internal_key.is_some());
+    let psbt = create_and_update_psbt(vec![utxo], &wallet, vec![(receive, 49_800)], change, 0, absolute::LockTime::ZERO, Sequence::ENABLE_RBF_NO_LOCKTIME).unwrap();
+    assert_eq!(psbt.unsigned_tx.output.len(), 1);
+    assert_eq!(psbt.outputs.len(), 1);
+}
+
+/// A server that knows the given transactions and estimates 2 sat/vB for every target.
+#[cfg(test)]
+struct Mempool(Vec<Transaction>);
+
+#[cfg(test)]
+impl ChainSource for Mempool {
+    fn tip(&self) -> Result<u32, Box<dyn Error>> { Ok(800_001) }
+    fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<crate::server::source::History>, Box<dyn Error>> { Ok(vec![Vec::new(); scripts.len()]) }
+    fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        Ok(txids.iter().filter_map(|txid| self.0.iter().find(|tx| tx.txid() == *txid).cloned()).collect())
+    }
+    fn fee_estimate(&self, _blocks: usize) -> Result<f64, Box<dyn Error>> { Ok(2.) }
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> { Ok(tx.txid()) }
+}
+
+#[test]
+fn test_make_errors() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let receive = cache.script(&wallet, true, 0).unwrap();
+    let funding = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 50_000, script_pubkey: receive }] };
+    cache.insert_transaction(&funding, 800_000);
+    cache.update_utxos().unwrap();
+    let source = Mempool(Vec::new());
+    let payee = "bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a".to_string();
+    let (any_coin, options) = (CoinControl::default(), TxOptions::default());
+    let coin = CoinControl { include: vec![OutPoint { txid: funding.txid(), vout: 0 }], ..Default::default() };
+    let unknown = CoinControl { include: vec![OutPoint { txid: funding.txid(), vout: 1 }], ..Default::default() };
+    let two_max = [Recipient { address: payee.clone(), amount: None }, Recipient { address: payee.clone(), amount: None }];
+    let too_much = [Recipient { address: payee.clone(), amount: Some(60_000) }];
+    let payment = [Recipient { address: payee, amount: Some(10_000) }];
+    let testnet = [Recipient { address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".into(), amount: Some(10_000) }];
+    assert!(make_and_send_to_ledger(&payment, &any_coin, &options, &wallet, &mut cache, 1, "largest", &source).is_ok());
+    // every mistake is returned to the caller instead of ending the program
+    assert!(make_and_send_to_ledger(&two_max, &any_coin, &options, &wallet, &mut cache, 1, "largest", &source).is_err());
+    assert!(make_and_send_to_ledger(&payment, &unknown, &options, &wallet, &mut cache, 1, "largest", &source).is_err());
+    assert!(make_and_send_to_ledger(&too_much, &coin, &options, &wallet, &mut cache, 1, "largest", &source).is_err());
+    assert!(make_and_send_to_ledger(&testnet, &any_coin, &options, &wallet, &mut cache, 1, "largest", &source).is_err());
+}
+
+#[test]
+fn test_excluded_coins() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let mut outpoints = Vec::new();
+    for (index, value) in [(0, 50_000), (1, 30_000)] {
+        let funding = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value, script_pubkey: cache.script(&wallet, true, index).unwrap() }] };
+        cache.insert_transaction(&funding, 800_000);
+        outpoints.push(OutPoint { txid: funding.txid(), vout: 0 });
+    }
+    cache.update_utxos().unwrap();
+    let source = Mempool(Vec::new());
+    let payment = [Recipient { address: "bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a".into(), amount: Some(10_000) }];
+    // the largest coin would be chosen, but it is frozen
+    let frozen = CoinControl { exclude: vec![outpoints[0]], ..Default::default() };
+    let psbt = make_and_send_to_ledger(&payment, &frozen, &TxOptions::default(), &wallet, &mut cache, 1, "largest", &source).unwrap();
+    let inputs: Vec<OutPoint> = psbt.unsigned_tx.input.iter().map(|input| input.previous_output).collect();
+    assert_eq!(inputs, vec![outpoints[1]]);
+    let all = CoinControl { exclude: outpoints, ..Default::default() };
+    assert!(make_and_send_to_ledger(&payment, &all, &TxOptions::default(), &wallet, &mut cache, 1, "largest", &source).is_err());
+}
+
+#[test]
+fn test_sweep() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    for (index, value) in [(0, 50_000), (1, 30_000)] {
+        let funding = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value, script_pubkey: cache.script(&wallet, true, index).unwrap() }] };
+        cache.insert_transaction(&funding, 800_000);
+    }
+    cache.update_utxos().unwrap();
+    let source = Mempool(Vec::new());
+    let payee = Address::from_str("bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a").unwrap().assume_checked();
+    let max = [Recipient { address: payee.to_string(), amount: None }];
+    let psbt = make_and_send_to_ledger(&max, &CoinControl::default(), &TxOptions::default(), &wallet, &mut cache, 1, "largest", &source).unwrap();
+    // every coin is spent to the recipient and the fee at 2 sat/vB, with no change
+    let fee = psbt.fee().unwrap().to_sat();
+    assert_eq!(psbt.unsigned_tx.input.len(), 2);
+    assert_eq!(psbt.unsigned_tx.output.len(), 1);
+    assert_eq!(psbt.unsigned_tx.output[0].script_pubkey, payee.script_pubkey());
+    assert_eq!(psbt.unsigned_tx.output[0].value, 80_000 - fee);
+    assert_eq!(fee, estimated_vsize(&psbt, &wallet).unwrap() * 2);
+    // the maximum goes to one recipient after the others are paid
+    let split = [Recipient { address: payee.to_string(), amount: Some(10_000) }, Recipient { address: payee.to_string(), amount: None }];
+    let psbt = make_and_send_to_ledger(&split, &CoinControl::default(), &TxOptions::default(), &wallet, &mut cache, 1, "largest", &source).unwrap();
+    assert_eq!(psbt.unsigned_tx.output.len(), 2);
+    assert_eq!(psbt.unsigned_tx.output[0].value, 10_000);
+    assert_eq!(psbt.unsigned_tx.output[1].value, 70_000 - psbt.fee().unwrap().to_sat());
+    let both = [Recipient { address: payee.to_string(), amount: None }, Recipient { address: payee.to_string(), amount: None }];
+    assert!(make_and_send_to_ledger(&both, &CoinControl::default(), &TxOptions::default(), &wallet, &mut cache, 1, "largest", &source).is_err());
+}
+
+#[test]
+fn test_bump() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let receive = cache.script(&wallet, true, 0).unwrap();
+    let change = cache.script(&wallet, false, 0).unwrap();
+    let payee = Address::from_str("bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a").unwrap().assume_checked();
+    let funding = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 50_000, script_pubkey: receive }] };
+    let input = TxIn { previous_output: OutPoint { txid: funding.txid(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::ENABLE_RBF_NO_LOCKTIME, witness: Witness::new() };
+    let spend = Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![input],
+        output: vec![TxOut { value: 20_000, script_pubkey: payee.script_pubkey() }, TxOut { value: 29_800, script_pubkey: change.clone() }],
+    };
+    cache.insert_transaction(&funding, 800_000);
+    cache.insert_transaction(&spend, 0);
+    cache.update_utxos().unwrap();
+    cache.tip = 800_001;
+    let source = Mempool(vec![spend.clone()]);
+    let psbt = make_bump(spend.txid(), &wallet, &mut cache, 1, &[], &source).unwrap();
+    assert_eq!(psbt.unsigned_tx.input[0].previous_output, spend.input[0].previous_output);
+    assert_eq!(psbt.unsigned_tx.output[0], spend.output[0]);
+    assert_eq!(psbt.unsigned_tx.output[1].script_pubkey, change);
+    // the original paid 200 sats for 113 vbytes without a witness, so the 141 vbyte replacement pays a sat/vB more
+    let fee = psbt.fee().unwrap().to_sat();
+    assert!(fee >= 200 + 141);
+    assert!(fee as f64 / 141. >= 200. / 113. + 1.);
+    assert!(psbt.unsigned_tx.is_explicitly_rbf());
+    let absolute::LockTime::Blocks(height) = psbt.unsigned_tx.lock_time else { panic!("the locktime is a height") };
+    assert!((799_902..=800_001).contains(&height.to_consensus_u32()));
+    let confirmed = Mempool(vec![funding.clone()]);
+    assert!(make_bump(funding.txid(), &wallet, &mut cache, 1, &[], &confirmed).is_err());
+    // a send without change needs another coin to pay more, and unconfirmed or frozen ones cannot be added
+    let sweep = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: spend.input.clone(), output: vec![TxOut { value: 49_800, script_pubkey: payee.script_pubkey() }] };
+    let incoming = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 40_000, script_pubkey: cache.script(&wallet, true, 1).unwrap() }] };
+    cache.transactions.remove(&spend.txid());
+    cache.insert_transaction(&sweep, 0);
+    cache.insert_transaction(&incoming, 0);
+    cache.update_utxos().unwrap();
+    let source = Mempool(vec![sweep.clone()]);
+    assert!(make_bump(sweep.txid(), &wallet, &mut cache, 1, &[], &source).is_err());
+    let deposit = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 30_000, script_pubkey: cache.script(&wallet, true, 2).unwrap() }] };
+    cache.insert_transaction(&deposit, 800_000);
+    cache.update_utxos().unwrap();
+    let deposit_coin = OutPoint { txid: deposit.txid(), vout: 0 };
+    assert!(make_bump(sweep.txid(), &wallet, &mut cache, 1, &[deposit_coin], &source).is_err());
+    let psbt = make_bump(sweep.txid(), &wallet, &mut cache, 1, &[], &source).unwrap();
+    let inputs: Vec<OutPoint> = psbt.unsigned_tx.input.iter().map(|input| input.previous_output).collect();
+    assert_eq!(inputs, vec![spend.input[0].previous_output, deposit_coin]);
+}
+
+#[test]
+fn test_cpfp() {
+    struct Node(Mempool, u64);
+    impl ChainSource for Node {
+        fn tip(&self) -> Result<u32, Box<dyn Error>> { self.0.tip() }
+        fn script_histories(&self, scripts: &[ScriptBuf]) -> Result<Vec<crate::server::source::History>, Box<dyn Error>> { self.0.script_histories(scripts) }
+        fn transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Box<dyn Error>> { self.0.transactions(txids) }
+        fn mempool_fee(&self, _txid: &Txid) -> Result<Option<u64>, Box<dyn Error>> { Ok(Some(self.1)) }
+        fn fee_estimate(&self, blocks: usize) -> Result<f64, Box<dyn Error>> { self.0.fee_estimate(blocks) }
+        fn broadcast(&self, tx: &Transaction) -> Result<Txid, Box<dyn Error>> { self.0.broadcast(tx) }
+    }
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let payee = Address::from_str("bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a").unwrap().assume_checked();
+    // a payment received with a 100 sat fee
+    let outside = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 40_100, script_pubkey: payee.script_pubkey() }] };
+    let incoming = Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn { previous_output: OutPoint { txid: outside.txid(), vout: 0 }, ..Default::default() }],
+        output: vec![TxOut { value: 40_000, script_pubkey: cache.script(&wallet, true, 0).unwrap() }],
+    };
+    cache.insert_transaction(&incoming, 0);
+    cache.update_utxos().unwrap();
+    cache.tip = 800_001;
+    let source = Mempool(vec![outside, incoming.clone()]);
+    let child = make_cpfp(incoming.txid(), &wallet, &mut cache, 1, &[], &source).unwrap();
+    assert_eq!(child.unsigned_tx.input.len(), 1);
+    assert_eq!(child.unsigned_tx.input[0].previous_output.txid, incoming.txid());
+    assert_eq!(child.unsigned_tx.output.len(), 1);
+    assert!(!child.outputs[0].bip32_derivation.is_empty());
+    // together they pay 2 sat/vB for the parent and the 110 vbyte child
+    let child_fee = child.fee().unwrap().to_sat();
+    assert_eq!(child_fee + 100, 2 * (incoming.vsize() as u64 + 110));
+    // a node without a transaction index reports the parent's fee from its mempool instead
+    let node = Node(Mempool(vec![incoming.clone()]), 100);
+    assert!(make_cpfp(incoming.txid(), &wallet, &mut cache, 1, &[], &node.0).is_err());
+    let child = make_cpfp(incoming.txid(), &wallet, &mut cache, 1, &[], &node).unwrap();
+    assert_eq!(child.fee().unwrap().to_sat(), child_fee);
+    let frozen = [OutPoint { txid: incoming.txid(), vout: 0 }];
+    assert!(make_cpfp(incoming.txid(), &wallet, &mut cache, 1, &frozen, &node).is_err());
+}
+
+#[test]
+fn test_cancel() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let receive = cache.script(&wallet, true, 0).unwrap();
+    let change = cache.script(&wallet, false, 0).unwrap();
+    let payee = Address::from_str("bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a").unwrap().assume_checked();
+    let funding = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 50_000, script_pubkey: receive }] };
+    let input = TxIn { previous_output: OutPoint { txid: funding.txid(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::ENABLE_RBF_NO_LOCKTIME, witness: Witness::new() };
+    let spend = Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![input],
+        output: vec![TxOut { value: 20_000, script_pubkey: payee.script_pubkey() }, TxOut { value: 29_800, script_pubkey: change }],
+    };
+    cache.insert_transaction(&funding, 800_000);
+    cache.insert_transaction(&spend, 0);
+    cache.update_utxos().unwrap();
+    cache.tip = 800_001;
+    let source = Mempool(vec![spend.clone()]);
+    let cancel = make_cancel(spend.txid(), &wallet, &mut cache, 1, &source).unwrap();
+    assert_eq!(cancel.unsigned_tx.input.len(), 1);
+    assert_eq!(cancel.unsigned_tx.input[0].previous_output, spend.input[0].previous_output);
+    assert_eq!(cancel.unsigned_tx.output.len(), 1);
+    assert!(!cancel.outputs[0].bip32_derivation.is_empty());
+    assert!(cancel.fee().unwrap().to_sat() >= 200 + 110);
+    let confirmed = Mempool(vec![funding.clone()]);
+    assert!(make_cancel(funding.txid(), &wallet, &mut cache, 1, &confirmed).is_err());
+}
+
+#[test]
+fn test_consolidation() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let mut outpoints = Vec::new();
+    for (index, value) in [(0, 29_800), (1, 40_000)] {
+        let funding = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value, script_pubkey: cache.script(&wallet, true, index).unwrap() }] };
+        cache.insert_transaction(&funding, 800_000);
+        outpoints.push(OutPoint { txid: funding.txid(), vout: 0 });
+    }
+    cache.update_utxos().unwrap();
+    let source = Mempool(Vec::new());
+    // both coins merge into one output
+    let merged = make_consolidation(&wallet, &mut cache, 1., 20., None, &[], &source).unwrap();
+    assert_eq!(merged.unsigned_tx.input.len(), 2);
+    assert_eq!(merged.unsigned_tx.output.len(), 1);
+    assert_eq!(merged.unsigned_tx.output[0].value, 69_800 - merged.fee().unwrap().to_sat());
+    assert!(!merged.outputs[0].bip32_derivation.is_empty());
+    // only one coin is worth less than 30,000, and a frozen coin stays where it is
+    assert!(make_consolidation(&wallet, &mut cache, 1., 20., Some(30_000), &[], &source).is_err());
+    assert!(make_consolidation(&wallet, &mut cache, 1., 20., None, &outpoints[..1], &source).is_err());
+}
+
+#[test]
+fn test_locktime() {
+    let zpub = "zpub6qVc2FELq8mG3pf2eayaVtFtG3ots5wT9G82V8tSWUcXM54dZSgLvz23vEkqqQyB2rxNum7W94dLG7qUEE1RDNuKhgRi9EXhXZ6E6zxx7Kx";
+    let descriptor = single_sig_descriptor("7c7a8f2b", zpub, Network::Bitcoin).unwrap();
+    let wallet = WalletDescriptor::new(&descriptor, Network::Bitcoin).unwrap();
+    let mut cache = WalletCache { descriptor: wallet.descriptor.to_string(), ..Default::default() };
+    let deposit = Transaction { version: 2, lock_time: absolute::LockTime::ZERO, input: vec![TxIn::default()], output: vec![TxOut { value: 30_000, script_pubkey: cache.script(&wallet, true, 0).unwrap() }] };
+    cache.insert_transaction(&deposit, 800_000);
+    cache.update_utxos().unwrap();
+    cache.tip = 800_001;
+    let source = Mempool(Vec::new());
+    // a payment locked until a later height, spending coins that must have 144 confirmations
+    let payment = [Recipient { address: "bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a".into(), amount: Some(10_000) }];
+    let coin_control = CoinControl { include: vec![OutPoint { txid: deposit.txid(), vout: 0 }], ..Default::default() };
+    let options = TxOptions { lock_time: Some(absolute::LockTime::from_consensus(800_100)), sequence: Some(Sequence::from_height(144)) };
+    let delayed = make(&payment, &coin_control, &options, &wallet, &mut cache, 1, "largest", &source).unwrap();
+    assert_eq!(delayed.unsigned_tx.lock_time, absolute::LockTime::from_consensus(800_100));
+    assert!(delayed.unsigned_tx.input.iter().all(|input| input.sequence == Sequence::from_height(144)));
+    // a relative lock in blocks still signals replace-by-fee
+    assert!(delayed.unsigned_tx.is_explicitly_rbf());
+    // without options the locktime is near the tip
+    let current = make(&payment, &coin_control, &TxOptions::default(), &wallet, &mut cache, 1, "largest", &source).unwrap();
+    let absolute::LockTime::Blocks(height) = current.unsigned_tx.lock_time else { panic!("the locktime is a height") };
+    assert!((799_902..=800_001).contains(&height.to_consensus_u32()));
+}
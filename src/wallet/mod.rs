@@ -1 +1,3 @@
-pub mod actions;
\ No newline at end of file
+pub mod actions;
+pub mod selection;
+pub mod uri;
@@ -0,0 +1,231 @@
+use bitcoin::VarInt;
+use rand::seq::SliceRandom;
+use crate::wallet::actions::SelectionUTXO;
+
+/// Branch and bound gives up after this many steps, like Bitcoin Core.
+const BNB_TOTAL_TRIES: usize = 100_000;
+
+/// The coin selection algorithms that can be chosen by name when sending.
+pub const ALGORITHMS: [&str; 5] = ["largest", "smallest", "bnb", "random", "waste"];
+
+/// What a selection has to pay for. Sizes are in weight units and fee rates in Satoshis per virtual byte.
+#[derive(Debug, Clone)]
+pub struct SelectionParams {
+    /// The amount sent to the recipients.
+    pub target: u64,
+    pub fee_rate: f64,
+    /// The fee rate expected when coins are spent later, used to decide if spending them now is wasteful.
+    pub long_term_fee_rate: f64,
+    /// The transaction without inputs or a change output, with room for a one byte input count.
+    pub base_weight: u64,
+    pub input_weight: u64,
+    pub change_weight: u64,
+    /// The dust limit of the change script.
+    pub change_dust: u64,
+}
+
+impl SelectionParams {
+    /// The size in virtual bytes of a transaction with this many inputs.
+    pub fn vsize(&self, inputs: usize, change: bool) -> u64 {
+        let change_weight = if change { self.change_weight } else { 0 };
+        let counter_weight = 4 * (VarInt(inputs as u64).len() as u64 - 1);
+        let weight = self.base_weight + self.input_weight * inputs as u64 + change_weight + counter_weight;
+        weight.div_ceil(4)
+    }
+
+    /// The fee for a transaction with this many inputs.
+    pub fn fee(&self, inputs: usize, change: bool) -> u64 {
+        (self.vsize(inputs, change) as f64 * self.fee_rate).ceil() as u64
+    }
+
+    /// The value of a coin after paying for its own input.
+    fn effective_value(&self, coin: &SelectionUTXO) -> i64 {
+        coin.value as i64 - (self.input_weight as f64 / 4. * self.fee_rate).ceil() as i64
+    }
+
+    /// What it costs to create a change output now and spend it later.
+    pub fn cost_of_change(&self) -> u64 {
+        (self.change_weight as f64 / 4. * self.fee_rate + self.input_weight as f64 / 4. * self.long_term_fee_rate).ceil() as u64
+    }
+
+    /// The smallest change worth an output: above the dust limit and worth more than it costs to spend.
+    pub fn min_change(&self) -> u64 {
+        self.change_dust.max((self.input_weight as f64 / 4. * self.fee_rate).ceil() as u64)
+    }
+
+    /// The change left over once the coins pay for the target and fees, which is zero when the
+    /// coins cover the target without a change output, with any excess smaller than `min_change`
+    /// going to the fee. None when the coins fall short.
+    pub fn change(&self, coins: &[&SelectionUTXO]) -> Option<u64> {
+        let total: u64 = coins.iter().map(|coin| coin.value).sum();
+        let with_change = self.target + self.fee(coins.len(), true);
+        if total >= with_change + self.min_change() {
+            Some(total - with_change)
+        } else if total >= self.target + self.fee(coins.len(), false) {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Bitcoin Core's waste metric: what the inputs cost now compared to later, plus the cost of
+    /// the change output, or the excess given to miners when there is no change.
+    pub fn waste(&self, coins: &[&SelectionUTXO]) -> Option<f64> {
+        let change = self.change(coins)?;
+        let timing = coins.len() as f64 * self.input_weight as f64 / 4. * (self.fee_rate - self.long_term_fee_rate);
+        if change > 0 {
+            Some(timing + self.cost_of_change() as f64)
+        } else {
+            let total: u64 = coins.iter().map(|coin| coin.value).sum();
+            Some(timing + (total - self.target - self.fee(coins.len(), false)) as f64)
+        }
+    }
+}
+
+/// Adds coins in the given order until the target and fees are covered.
+fn accumulate(coins: &[SelectionUTXO], order: Vec<usize>, params: &SelectionParams) -> Option<Vec<usize>> {
+    let mut selected = Vec::new();
+    for index in order {
+        selected.push(index);
+        let chosen: Vec<&SelectionUTXO> = selected.iter().map(|i| &coins[*i]).collect();
+        if params.change(&chosen).is_some() {
+            return Some(selected);
+        }
+    }
+    None
+}
+
+pub fn largest_first(coins: &[SelectionUTXO], params: &SelectionParams) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..coins.len()).collect();
+    order.sort_by(|a, b| coins[*b].value.cmp(&coins[*a].value));
+    accumulate(coins, order, params)
+}
+
+pub fn smallest_first(coins: &[SelectionUTXO], params: &SelectionParams) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..coins.len()).collect();
+    order.sort_by(|a, b| coins[*a].value.cmp(&coins[*b].value));
+    accumulate(coins, order, params)
+}
+
+/// Single random draw: coins are added in a random order, which avoids revealing a pattern.
+pub fn single_random_draw(coins: &[SelectionUTXO], params: &SelectionParams) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..coins.len()).collect();
+    order.shuffle(&mut rand::thread_rng());
+    accumulate(coins, order, params)
+}
+
+/// Bitcoin Core's branch and bound. Searches for a set of coins that pays the target and fees
+/// without a change output, overpaying by less than the change would cost, and with the least waste.
+pub fn branch_and_bound(coins: &[SelectionUTXO], params: &SelectionParams) -> Option<Vec<usize>> {
+    let mut pool: Vec<(usize, i64)> = coins.iter().enumerate()
+        .map(|(i, coin)| (i, params.effective_value(coin)))
+        .filter(|(_, value)| *value > 0)
+        .collect();
+    pool.sort_by_key(|(_, value)| std::cmp::Reverse(*value));
+    let target = (params.target + params.fee(0, false)) as i64;
+    let upper = target + params.cost_of_change() as i64;
+    let input_waste = params.input_weight as f64 / 4. * (params.fee_rate - params.long_term_fee_rate);
+    let mut remaining: i64 = pool.iter().map(|(_, value)| value).sum();
+    if remaining < target {
+        return None;
+    }
+    let mut included: Vec<bool> = Vec::new();
+    let mut value = 0;
+    let mut waste = 0.;
+    let mut best: Option<(Vec<bool>, f64)> = None;
+    for _ in 0..BNB_TOTAL_TRIES {
+        let best_waste = best.as_ref().map_or(f64::MAX, |(_, waste)| *waste);
+        let backtrack = if value + remaining < target || value > upper || (waste > best_waste && input_waste > 0.) {
+            true
+        } else if value >= target {
+            // effective values round each input's fee, so the excess is taken from the fee for the whole transaction
+            let chosen: Vec<&SelectionUTXO> = included.iter().zip(&pool).filter(|(chosen, _)| **chosen).map(|(_, (i, _))| &coins[*i]).collect();
+            let total: u64 = chosen.iter().map(|coin| coin.value).sum();
+            let excess = total as i64 - (params.target + params.fee(chosen.len(), false)) as i64;
+            if excess >= 0 && excess <= params.cost_of_change() as i64 && waste + excess as f64 <= best_waste {
+                best = Some((included.clone(), waste + excess as f64));
+            }
+            true
+        } else {
+            false
+        };
+        if backtrack {
+            // undo the most recent inclusions that were already explored, then try excluding the last one
+            while included.last() == Some(&false) {
+                included.pop();
+                remaining += pool[included.len()].1;
+            }
+            if included.is_empty() {
+                break;
+            }
+            let last = included.len() - 1;
+            included[last] = false;
+            value -= pool[last].1;
+            waste -= input_waste;
+        } else {
+            let next = included.len();
+            remaining -= pool[next].1;
+            included.push(true);
+            value += pool[next].1;
+            waste += input_waste;
+        }
+    }
+    let (included, _) = best?;
+    Some(included.iter().enumerate().filter(|(_, chosen)| **chosen).map(|(i, _)| pool[i].0).collect())
+}
+
+/// Runs every algorithm and keeps the selection with the least waste.
+pub fn least_waste(coins: &[SelectionUTXO], params: &SelectionParams) -> Option<Vec<usize>> {
+    let candidates = [branch_and_bound(coins, params), single_random_draw(coins, params), largest_first(coins, params), smallest_first(coins, params)];
+    candidates.into_iter().flatten()
+        .filter_map(|selection| {
+            let chosen: Vec<&SelectionUTXO> = selection.iter().map(|i| &coins[*i]).collect();
+            Some((params.waste(&chosen)?, selection))
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, selection)| selection)
+}
+
+#[test]
+fn test_coin_selection() {
+    use std::str::FromStr;
+    use bitcoin::{Address, ScriptBuf, Txid, hashes::Hash};
+    use crate::wallet::actions::AddressInfo;
+    let address = Address::from_str("bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a").unwrap().assume_checked();
+    let coins: Vec<SelectionUTXO> = [100_000, 60_000, 30_000, 22_000, 7_000].iter().enumerate().map(|(i, value)| SelectionUTXO {
+        id: Txid::all_zeros(),
+        index: i,
+        value: *value,
+        script: ScriptBuf::new(),
+        info: AddressInfo { address: address.clone(), external: true, index: 0 },
+    }).collect();
+    let params = SelectionParams { target: 50_000, fee_rate: 10., long_term_fee_rate: 5., base_weight: 168, input_weight: 272, change_weight: 124, change_dust: 294 };
+    // 30,000 and 22,000 pay 50,000 plus 1,780 in fees with 220 left over, less than a change output costs
+    let mut bnb = branch_and_bound(&coins, &params).unwrap();
+    bnb.sort();
+    assert_eq!(bnb, vec![2, 3]);
+    let chosen: Vec<&SelectionUTXO> = bnb.iter().map(|i| &coins[*i]).collect();
+    assert_eq!(params.change(&chosen), Some(0));
+    assert_eq!(largest_first(&coins, &params), Some(vec![0]));
+    let random = single_random_draw(&coins, &params).unwrap();
+    let chosen: Vec<&SelectionUTXO> = random.iter().map(|i| &coins[*i]).collect();
+    assert!(params.change(&chosen).is_some());
+    // the changeless selection wastes less than paying for change
+    let mut best = least_waste(&coins, &params).unwrap();
+    best.sort();
+    assert_eq!(best, vec![2, 3]);
+    // 500 left over after paying for change is worth less than the 680 it costs to spend
+    let dust = SelectionParams { target: 98_090, ..params.clone() };
+    assert_eq!(params.change(&[&coins[0]]), Some(48_590));
+    assert_eq!(dust.change(&[&coins[0]]), Some(0));
+    let too_much = SelectionParams { target: 300_000, ..params };
+    assert!(branch_and_bound(&coins, &too_much).is_none());
+    assert!(least_waste(&coins, &too_much).is_none());
+    // a taproot coin whose effective value covers the target, while the fee rounded over the whole transaction does not
+    let taproot = SelectionParams { target: 50_000, fee_rate: 10., long_term_fee_rate: 5., base_weight: 214, input_weight: 231, change_weight: 172, change_dust: 330 };
+    for (value, selected) in [(51_118, false), (51_119, false), (51_120, true)] {
+        let coin = [SelectionUTXO { value, ..coins[0].clone() }];
+        assert_eq!(branch_and_bound(&coin, &taproot).is_some(), selected);
+        assert_eq!(taproot.change(&[&coin[0]]).is_some(), selected);
+    }
+}
@@ -0,0 +1,93 @@
+use std::{error::Error, io};
+use bitcoin::{Address, Amount, Denomination};
+
+/// A BIP21 payment request, e.g. `bitcoin:bc1q...?amount=0.001&label=Coffee`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentRequest {
+    pub address: String,
+    pub amount: Option<u64>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+pub fn is_payment_uri(uri: &str) -> bool {
+    uri.to_lowercase().starts_with("bitcoin:")
+}
+
+/// The BIP21 URI requesting a payment to the address. Without parameters it is upper case, which makes a smaller QR code.
+pub fn payment_uri(address: &Address, amount: Option<u64>, label: Option<&str>, message: Option<&str>) -> String {
+    let mut params = Vec::new();
+    if let Some(amount) = amount {
+        let btc = Amount::from_sat(amount).to_string_in(Denomination::Bitcoin);
+        let btc = if btc.contains('.') { btc.trim_end_matches('0').trim_end_matches('.').to_string() } else { btc };
+        params.push(format!("amount={btc}"));
+    }
+    if let Some(label) = label {
+        params.push(format!("label={}", encode(label)));
+    }
+    if let Some(message) = message {
+        params.push(format!("message={}", encode(message)));
+    }
+    if params.is_empty() {
+        address.to_qr_uri()
+    } else {
+        format!("bitcoin:{}?{}", address, params.join("&"))
+    }
+}
+
+/// Reads a BIP21 URI. Parameters starting with `req-` must be understood, so any of them is an error.
+pub fn parse_payment_uri(uri: &str) -> Result<PaymentRequest, Box<dyn Error>> {
+    if !is_payment_uri(uri) {
+        return Err(Box::new(io::Error::other("A payment URI starts with bitcoin:")));
+    }
+    let (address, query) = uri[8..].split_once('?').unwrap_or((&uri[8..], ""));
+    let mut request = PaymentRequest { address: address.to_string(), amount: None, label: None, message: None };
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        match key.to_lowercase().as_str() {
+            "amount" => request.amount = Some(Amount::from_str_in(value, Denomination::Bitcoin)?.to_sat()),
+            "label" => request.label = Some(decode(value)?),
+            "message" => request.message = Some(decode(value)?),
+            key if key.starts_with("req-") => return Err(Box::new(io::Error::other(format!("The payment requires {key}, which is not supported")))),
+            _ => {},
+        }
+    }
+    Ok(request)
+}
+
+fn encode(text: &str) -> String {
+    text.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+fn decode(text: &str) -> Result<String, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    let mut chars = text.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next().unwrap_or_default(), chars.next().unwrap_or_default()];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex)?, 16)?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[test]
+fn test_payment_uri() {
+    use std::str::FromStr;
+    let address = Address::from_str("bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a").unwrap().assume_checked();
+    assert_eq!(payment_uri(&address, None, None, None), "BITCOIN:BC1QV6UAUUVG0EM39263XKNAQSRQQQK0FH6Q4TH23A");
+    let uri = payment_uri(&address, Some(100_000), Some("Coffee & cake"), None);
+    assert_eq!(uri, "bitcoin:bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a?amount=0.001&label=Coffee%20%26%20cake");
+    let request = parse_payment_uri(&uri).unwrap();
+    assert_eq!(request.address, address.to_string());
+    assert_eq!(request.amount, Some(100_000));
+    assert_eq!(request.label.as_deref(), Some("Coffee & cake"));
+    assert_eq!(parse_payment_uri("BITCOIN:BC1QV6UAUUVG0EM39263XKNAQSRQQQK0FH6Q4TH23A?amount=1").unwrap().amount, Some(100_000_000));
+    assert!(parse_payment_uri("bitcoin:bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a?req-somethingyoudontunderstand=50").is_err());
+    assert!(parse_payment_uri("bitcoin:bc1qv6uauuvg0em39263xknaqsrqqqk0fh6q4th23a?somethingyoudontunderstand=50").is_ok());
+}